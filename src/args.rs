@@ -8,6 +8,11 @@ const APP_NAME: &str = "symbols";
 
 pub const VERBOSITY_ARG: &str = "verbosity";
 pub const CONFIG_FILE_ARG: &str = "config";
+pub const CACHE_PATH_ARG: &str = "cache-path";
+pub const TIMEOUT_ARG: &str = "timeout";
+pub const OUTPUT_FORMAT_ARG: &str = "output-format";
+
+pub const VERBOSITY_ENV: &str = "SYMBOLS_VERBOSE";
 
 pub const UPLOAD_SUBCOMMAND: &str = "upload";
 pub const UPLOAD_PATH_ARG: &str = "path";
@@ -17,6 +22,46 @@ pub const UPLOAD_SERVER_NAME_ARG: &str = "server";
 pub const UPLOAD_OUTPUT_DIR_ARG: &str = "output";
 pub const UPLOAD_S3_BUCKET_ARG: &str = "s3bucket";
 pub const UPLOAD_S3_REGION_ARG: &str = "s3region";
+pub const UPLOAD_WITH_SOURCES_ARG: &str = "with-sources";
+pub const UPLOAD_DECOMPRESS_DEBUG_ARG: &str = "decompress-debug";
+pub const UPLOAD_ASSEMBLE_DWP_ARG: &str = "assemble-dwp";
+pub const UPLOAD_FORMAT_ARG: &str = "format";
+pub const UPLOAD_JOBS_ARG: &str = "jobs";
+pub const UPLOAD_FAT_MACHO_ARG: &str = "fat-macho";
+pub const UPLOAD_ARCHIVES_ARG: &str = "archives";
+pub const UPLOAD_MANIFEST_ARG: &str = "manifest";
+pub const UPLOAD_PRODUCT_ARG: &str = "product";
+pub const UPLOAD_PRODUCT_VERSION_ARG: &str = "product-version";
+pub const UPLOAD_COMMENT_ARG: &str = "comment";
+
+pub const DOWNLOAD_SUBCOMMAND: &str = "download";
+pub const DOWNLOAD_ID_ARG: &str = "id";
+pub const DOWNLOAD_EXECUTABLE_ARG: &str = "executable";
+pub const DOWNLOAD_DEBUGINFO_ARG: &str = "debuginfo";
+pub const DOWNLOAD_SOURCE_BUNDLE_ARG: &str = "sourcebundle";
+pub const DOWNLOAD_MINI_DEBUG_INFO_ARG: &str = "minidebuginfo";
+pub const DOWNLOAD_DWP_ARG: &str = "dwp";
+pub const DOWNLOAD_FILE_NAME_ARG: &str = "filename";
+pub const DOWNLOAD_ARCH_ARG: &str = "arch";
+pub const DOWNLOAD_TYPE_ARG: &str = "type";
+pub const DOWNLOAD_OUTPUT_ARG: &str = "output";
+pub const DOWNLOAD_SERVER_NAME_ARG: &str = "server";
+
+pub const SOURCE_SUBCOMMAND: &str = "source";
+pub const SOURCE_ID_ARG: &str = "id";
+pub const SOURCE_PATH_ARG: &str = "path";
+pub const SOURCE_OBJECT_ARG: &str = "object";
+pub const SOURCE_OUTPUT_ARG: &str = "output";
+pub const SOURCE_SERVER_NAME_ARG: &str = "server";
+
+pub const SERVE_SUBCOMMAND: &str = "serve";
+pub const SERVE_PATH_ARG: &str = "path";
+pub const SERVE_LISTEN_ARG: &str = "listen";
+pub const SERVE_LOCAL_ONLY_ARG: &str = "local-only";
+
+pub const PRUNE_SUBCOMMAND: &str = "prune";
+pub const PRUNE_SERVER_NAME_ARG: &str = "server";
+pub const PRUNE_DRY_RUN_ARG: &str = "dry-run";
 
 pub const LOGIN_SUBCOMMAND: &str = "login";
 pub const LOGIN_SERVICE_ARG: &str = "service";
@@ -30,7 +75,8 @@ pub fn parse_args() -> clap::ArgMatches {
             clap::Arg::new(VERBOSITY_ARG)
                 .short('v')
                 .action(ArgAction::Count)
-                .help("Sets the level of verbosity"),
+                .help("Sets the level of verbosity")
+                .long_help("Sets the level of verbosity. Can also be set with the SYMBOLS_VERBOSE environment variable (e.g. SYMBOLS_VERBOSE=3 is the same as -vvv)"),
         )
         .arg(
             clap::Arg::new(CONFIG_FILE_ARG)
@@ -38,9 +84,40 @@ pub fn parse_args() -> clap::ArgMatches {
                 .long("config")
                 .value_name("FILE")
                 .help("Path to config file")
+                .env("SYMBOLS_CONFIG")
+                .required(false)
+                .action(ArgAction::Set)
+        )
+        .arg(
+            clap::Arg::new(CACHE_PATH_ARG)
+                .long("cache-path")
+                .value_name("DIR")
+                .help("Path to the local symbol cache")
+                .env("SYMBOLS_CACHE_PATH")
+                .required(false)
+                .action(ArgAction::Set)
+        )
+        .arg(
+            clap::Arg::new(TIMEOUT_ARG)
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Timeout for each transaction with a server")
+                .env("SYMBOLS_TIMEOUT")
+                .value_parser(clap::value_parser!(u64))
                 .required(false)
                 .action(ArgAction::Set)
         )
+        .arg(
+            clap::Arg::new(OUTPUT_FORMAT_ARG)
+                .long("output-format")
+                .value_name("FORMAT")
+                .help("Format of the results printed to stdout")
+                .long_help("Format of the results printed to stdout. 'json' prints one JSON object per line for each result and error, logs are always printed to stderr")
+                .env("SYMBOLS_OUTPUT_FORMAT")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .action(ArgAction::Set)
+        )
         .subcommand(
           clap::Command::new(UPLOAD_SUBCOMMAND)
                 .about("Upload the debug info files to a debug server")
@@ -62,17 +139,107 @@ pub fn parse_args() -> clap::ArgMatches {
                         .short('d')
                         .long("dry-run")
                         .action(ArgAction::SetTrue)
+                        .env("SYMBOLS_DRY_RUN")
+                        .value_parser(clap::builder::BoolishValueParser::new())
                         .help("Fake the upload part")
                         .long_help(
                             "Shows where the files would be uploaded, but does not run the upload",
                         ),
                 )
+                .arg(
+                    clap::Arg::new(UPLOAD_WITH_SOURCES_ARG)
+                        .long("with-sources")
+                        .action(ArgAction::SetTrue)
+                        .help("Also upload a source bundle for each debug info file")
+                        .long_help("Create a source bundle from the source files referenced by each debug info file and upload it next to the debug info file"),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_DECOMPRESS_DEBUG_ARG)
+                        .long("decompress-debug")
+                        .action(ArgAction::SetTrue)
+                        .help("Decompress compressed debug sections before upload")
+                        .long_help("Upload ELF files with compressed debug sections (e.g. built with -gz) with their debug sections decompressed, for consumers which cannot read compressed debug info"),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_ASSEMBLE_DWP_ARG)
+                        .long("assemble-dwp")
+                        .action(ArgAction::SetTrue)
+                        .help("Assemble DWARF packages from .dwo files")
+                        .long_help("For ELF files built with -gsplit-dwarf which have no DWARF package (<file>.dwp), assemble one from the .dwo files referenced by the file and upload it"),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_FORMAT_ARG)
+                        .long("format")
+                        .help("Format of the symbols to upload")
+                        .long_help("Format of the symbols to upload. 'native' uploads the files as they are found, 'breakpad' uploads Breakpad symbol files generated from each debug info file, and 'all' uploads both")
+                        .value_parser(["native", "breakpad", "all"])
+                        .default_value("native")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_FAT_MACHO_ARG)
+                        .long("fat-macho")
+                        .help("How to upload fat Mach-O files")
+                        .long_help("How to upload fat (universal) Mach-O files. 'slices' uploads each architecture as its own file, 'container' uploads the whole file under the key of each architecture")
+                        .value_parser(["slices", "container"])
+                        .default_value("slices")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_ARCHIVES_ARG)
+                        .long("archives")
+                        .help("How to upload static libraries")
+                        .long_help("How to upload static libraries (.a, .lib). 'skip' ignores them, 'members' uploads each member object with an identifier as its own file, 'container' uploads the whole library under the key of each such member. Members without an identifier are reported")
+                        .value_parser(["skip", "members", "container"])
+                        .default_value("skip")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_JOBS_ARG)
+                        .short('j')
+                        .long("jobs")
+                        .value_name("N")
+                        .help("Number of files to upload at the same time")
+                        .value_parser(clap::value_parser!(u16).range(1..))
+                        .default_value("8")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_MANIFEST_ARG)
+                        .long("manifest")
+                        .value_name("PATH")
+                        .help("Write a JSON manifest of the upload to PATH")
+                        .long_help("Write a JSON manifest to PATH with an entry for each file, recording its identifier, key, size, sha256 and whether it was uploaded, skipped because it already exists or failed")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_PRODUCT_ARG)
+                        .long("product")
+                        .help("Product recorded in the transaction of a path store")
+                        .long_help("Product recorded in the symstore transaction of the upload, for uploads to a path store. Like the /t option of symstore.exe")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_PRODUCT_VERSION_ARG)
+                        .long("product-version")
+                        .help("Version recorded in the transaction of a path store")
+                        .long_help("Version recorded in the symstore transaction of the upload, for uploads to a path store. Like the /v option of symstore.exe")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    clap::Arg::new(UPLOAD_COMMENT_ARG)
+                        .long("comment")
+                        .help("Comment recorded in the transaction of a path store")
+                        .long_help("Comment recorded in the symstore transaction of the upload, for uploads to a path store. Like the /c option of symstore.exe")
+                        .action(ArgAction::Set),
+                )
                 .arg(
                     clap::Arg::new(UPLOAD_SERVER_NAME_ARG)
                         .short('s')
                         .long("server")
                         .help("Name of server in config file")
                         .long_help("Specify which server in config file to upload files too")
+                        .env("SYMBOLS_SERVER")
                         .required(false)
                         .action(ArgAction::Set)
                 )
@@ -106,6 +273,192 @@ pub fn parse_args() -> clap::ArgMatches {
                 )
 
         )
+        .subcommand(
+            clap::Command::new(DOWNLOAD_SUBCOMMAND)
+                .about("Download a debug info file or executable from the configured servers")
+                .arg(
+                    clap::Arg::new(DOWNLOAD_ID_ARG)
+                        .help("Build-id, code-id or debug-id of the file to download")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_EXECUTABLE_ARG)
+                        .long("executable")
+                        .action(ArgAction::SetTrue)
+                        .help("Download the executable")
+                        .conflicts_with_all([DOWNLOAD_DEBUGINFO_ARG, DOWNLOAD_SOURCE_BUNDLE_ARG, DOWNLOAD_MINI_DEBUG_INFO_ARG, DOWNLOAD_DWP_ARG]),
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_DEBUGINFO_ARG)
+                        .long("debuginfo")
+                        .action(ArgAction::SetTrue)
+                        .help("Download the debug info (default)")
+                        .conflicts_with_all([DOWNLOAD_EXECUTABLE_ARG, DOWNLOAD_SOURCE_BUNDLE_ARG, DOWNLOAD_MINI_DEBUG_INFO_ARG, DOWNLOAD_DWP_ARG]),
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_SOURCE_BUNDLE_ARG)
+                        .long("sourcebundle")
+                        .action(ArgAction::SetTrue)
+                        .help("Download the source bundle")
+                        .conflicts_with_all([DOWNLOAD_EXECUTABLE_ARG, DOWNLOAD_DEBUGINFO_ARG, DOWNLOAD_MINI_DEBUG_INFO_ARG, DOWNLOAD_DWP_ARG]),
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_MINI_DEBUG_INFO_ARG)
+                        .long("minidebuginfo")
+                        .action(ArgAction::SetTrue)
+                        .help("Download the MiniDebugInfo")
+                        .conflicts_with_all([DOWNLOAD_EXECUTABLE_ARG, DOWNLOAD_DEBUGINFO_ARG, DOWNLOAD_SOURCE_BUNDLE_ARG, DOWNLOAD_DWP_ARG]),
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_DWP_ARG)
+                        .long("dwp")
+                        .action(ArgAction::SetTrue)
+                        .help("Download the DWARF package")
+                        .conflicts_with_all([DOWNLOAD_EXECUTABLE_ARG, DOWNLOAD_DEBUGINFO_ARG, DOWNLOAD_SOURCE_BUNDLE_ARG, DOWNLOAD_MINI_DEBUG_INFO_ARG]),
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_FILE_NAME_ARG)
+                        .short('f')
+                        .long("file-name")
+                        .help("Name of the file to download")
+                        .long_help("Name of the file to download (e.g. 'foo.pdb'). Needed for the files whose key contains their name, e.g. PE and PDB files in the default layout. Without --type, the file is looked up as a PE or PDB file rather than an ELF file")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_TYPE_ARG)
+                        .long("type")
+                        .help("Type of the file to download")
+                        .long_help("Type of the file to download. Defaults to 'elf' without a filename, 'pdb' for a '.pdb' filename and 'pe' for any other filename")
+                        .value_parser(["elf", "macho", "pe", "pdb"])
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_ARCH_ARG)
+                        .long("arch")
+                        .help("Architecture of the file to download (e.g. 'x86_64')")
+                        .long_help("Architecture of the file to download (e.g. 'x86_64'). Only needed for servers whose layout contains '{arch}'")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_OUTPUT_ARG)
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Path to write the downloaded file to")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(DOWNLOAD_SERVER_NAME_ARG)
+                        .short('s')
+                        .long("server")
+                        .help("Name of server in config file")
+                        .long_help("Only download from the given server in the config file")
+                        .env("SYMBOLS_SERVER")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+        )
+        .subcommand(
+            clap::Command::new(SOURCE_SUBCOMMAND)
+                .about("Download source files from the configured servers")
+                .arg(
+                    clap::Arg::new(SOURCE_ID_ARG)
+                        .help("Build-id of the file which references the source file")
+                        .required_unless_present(SOURCE_OBJECT_ARG)
+                        .requires(SOURCE_PATH_ARG)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new(SOURCE_PATH_ARG)
+                        .help("Absolute path of the source file at compile time")
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::new(SOURCE_OBJECT_ARG)
+                        .long("object")
+                        .value_name("FILE")
+                        .help("Download all source files referenced by the debug info of FILE")
+                        .conflicts_with_all([SOURCE_ID_ARG, SOURCE_OUTPUT_ARG])
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(SOURCE_OUTPUT_ARG)
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Path to write the downloaded file to")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(SOURCE_SERVER_NAME_ARG)
+                        .short('s')
+                        .long("server")
+                        .help("Name of server in config file")
+                        .long_help("Only download from the given server in the config file")
+                        .env("SYMBOLS_SERVER")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+        )
+        .subcommand(
+            clap::Command::new(SERVE_SUBCOMMAND)
+                .about("Serve symbols over HTTP as a debuginfod compatible server")
+                .arg(
+                    clap::Arg::new(SERVE_PATH_ARG)
+                        .short('p')
+                        .long("path")
+                        .value_name("DIR")
+                        .help("Folder of symbols to serve")
+                        .long_help("Folder of symbols to serve, laid out as created by 'upload --output-dir'. Requests not found in this folder are looked up in the configured servers")
+                        .required(false)
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(SERVE_LISTEN_ARG)
+                        .short('l')
+                        .long("listen")
+                        .value_name("ADDR")
+                        .help("Address to listen on")
+                        .default_value("127.0.0.1:8002")
+                        .action(ArgAction::Set)
+                )
+                .arg(
+                    clap::Arg::new(SERVE_LOCAL_ONLY_ARG)
+                        .long("local-only")
+                        .action(ArgAction::SetTrue)
+                        .help("Do not look up files in the configured servers")
+                        .requires(SERVE_PATH_ARG)
+                )
+        )
+        .subcommand(
+            clap::Command::new(PRUNE_SUBCOMMAND)
+            .about("Delete old builds from a server according to its retention policy")
+            .long_about("Delete old builds from a server according to its retention policy. Uploads to S3 and B2 servers are only recorded as builds once the server has a retention policy, so files uploaded before cannot be pruned")
+            .arg(
+                clap::Arg::new(PRUNE_SERVER_NAME_ARG)
+                    .short('s')
+                    .long("server")
+                    .help("Name of server in config file")
+                    .env("SYMBOLS_SERVER")
+                    .required(true)
+                    .action(ArgAction::Set)
+                )
+            .arg(
+                clap::Arg::new(PRUNE_DRY_RUN_ARG)
+                    .short('d')
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .env("SYMBOLS_DRY_RUN")
+                    .value_parser(clap::builder::BoolishValueParser::new())
+                    .help("List what would be deleted without deleting it")
+                )
+        )
         .subcommand(
             clap::Command::new(LOGIN_SUBCOMMAND)
             .about("Login to web services in order to download sources / symbols")
@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{info, trace};
+
+/// Local cache of downloaded files. Files are stored under the same key
+/// layout as on the servers, so a key maps directly to a path in the cache.
+pub struct Cache {
+    root: PathBuf,
+}
+
+impl Cache {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Returns the path of `key` in the cache if it has been cached.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = self.path(key);
+        if path.is_file() {
+            trace!("Cache hit for '{}'", key);
+            Some(path)
+        } else {
+            trace!("Cache miss for '{}'", key);
+            None
+        }
+    }
+
+    /// Adds `key` to the cache by calling `fetch` with a temporary path to
+    /// write to. The file is only moved into the cache if `fetch` returns
+    /// true, so partial downloads never show up as cache hits.
+    pub fn insert_with<F>(&self, key: &str, fetch: F) -> Result<Option<PathBuf>>
+    where
+        F: FnOnce(&Path) -> Result<bool>,
+    {
+        let path = self.path(key);
+        std::fs::create_dir_all(&self.root).context(format!(
+            "Failed to create cache folder '{}'",
+            self.root.display()
+        ))?;
+
+        // Write to a unique file at the root of the cache so that concurrent
+        // fetches of the same key do not write to the same file, and folders
+        // of the key are only created once there is a file to put in them.
+        let partial = self
+            .root
+            .join(format!(".{:08x}.part", rand::random::<u32>()));
+
+        match fetch(&partial) {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = std::fs::remove_file(&partial);
+                return Ok(None);
+            }
+            Err(error) => {
+                let _ = std::fs::remove_file(&partial);
+                return Err(error);
+            }
+        }
+        let moved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::rename(&partial, &path));
+        if let Err(error) = moved {
+            let _ = std::fs::remove_file(&partial);
+            return Err(error).context(format!(
+                "Failed to move '{}' into the cache at '{}'",
+                partial.display(),
+                path.display()
+            ));
+        }
+        info!("Cached '{}' at '{}'", key, path.display());
+        Ok(Some(path))
+    }
+}
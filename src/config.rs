@@ -4,8 +4,16 @@ use log::{/*error,*/ /*debug,*/ info, /* trace,*/ warn};
 
 use serde::Deserialize;
 
+use crate::symstore::layout::Layout;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    /// Path to the local cache. Defaults to the OS cache folder.
+    pub cache: Option<PathBuf>,
+
+    /// Timeout in seconds for each transaction with a server.
+    pub timeout: Option<u64>,
+
     #[serde(default = "default_servers")]
     pub servers: std::vec::Vec<RemoteStorage>,
 }
@@ -20,6 +28,10 @@ pub struct S3Config {
     pub region: String,
 
     pub profile: Option<String>,
+
+    /// Size of the parts of a multipart upload, in MiB. Files larger than a
+    /// part are uploaded in parts.
+    pub part_size_mib: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,6 +42,10 @@ pub struct B2Config {
     pub prefix: String,
     pub endpoint: String,
     pub account_id: Option<String>,
+
+    /// Size of the parts of a multipart upload, in MiB. Files larger than a
+    /// part are uploaded in parts.
+    pub part_size_mib: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -41,11 +57,45 @@ pub struct SymbolServerConfig {
 #[derive(Debug, Deserialize)]
 pub struct HttpConfig {
     pub url: String,
+
+    /// Method used to upload files.
+    #[serde(default)]
+    pub method: HttpMethod,
+
+    pub auth: Option<HttpAuth>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMethod {
+    #[default]
+    Put,
+    Post,
+}
+
+/// Authentication for HTTP servers. Secrets are read from environment
+/// variables so they never have to be written to the config file.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HttpAuth {
+    Basic {
+        username: String,
+        password_env: Option<String>,
+    },
+    Bearer {
+        token_env: String,
+    },
 }
 
 #[derive(Debug, Deserialize)]
 pub struct PathConfig {
     pub path: PathBuf,
+
+    /// Store the files in two tiers like symstore.exe does for an
+    /// `index2.txt` store, e.g. `fo/foo.pdb/<id>/foo.pdb`. A store with an
+    /// `index2.txt` is always read and written in two tiers.
+    #[serde(default)]
+    pub two_tier: bool,
 }
 
 #[derive(Debug, Deserialize, Eq, PartialEq)]
@@ -62,10 +112,72 @@ pub struct RemoteStorage {
 
     pub name: Option<String>,
 
+    /// How failed operations with the server are retried.
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Layout of the files on the server, i.e. the key of each file.
+    #[serde(default)]
+    pub layout: Layout,
+
+    /// Which builds `prune` keeps. Uploads to S3 and B2 servers only record
+    /// transactions when a retention policy is configured.
+    pub retention: Option<RetentionConfig>,
+
     #[serde(flatten)]
     pub storage_type: RemoteStorageType,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Number of times an operation is attempted before giving up.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry in milliseconds. The delay doubles with
+    /// each retry, with random jitter.
+    pub initial_backoff_ms: u64,
+
+    /// Upper bound of the delay between two attempts in milliseconds.
+    pub max_backoff_ms: u64,
+
+    /// HTTP status codes which are retried.
+    pub retry_statuses: Vec<u16>,
+
+    /// Whether transient I/O errors (e.g. timeouts or reset connections) are
+    /// retried.
+    pub retry_io_errors: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+            retry_io_errors: true,
+        }
+    }
+}
+
+/// Retention policy of a server. A build is the transaction recorded by an
+/// upload, and a build is deleted only if none of the rules keeps it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Builds older than this many days are deleted. Without it every build
+    /// which is not kept by another rule is deleted.
+    pub max_age_days: Option<u64>,
+
+    /// Number of the newest builds of each product which are always kept.
+    pub keep_last: Option<usize>,
+
+    /// Versions which are always kept, e.g. tagged releases. `*` and `?`
+    /// match any characters and any single character.
+    pub keep_versions: Vec<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum RemoteStorageType {
@@ -85,9 +197,36 @@ pub enum RemoteStorageType {
     Path(PathConfig),
 }
 
+impl RemoteStorage {
+    /// Returns where the files of the server are stored, e.g. its URL.
+    pub fn location(&self) -> String {
+        match &self.storage_type {
+            RemoteStorageType::Http(c) => c.url.clone(),
+            RemoteStorageType::S3(c) => format!("s3://{}/{}", c.bucket, c.prefix),
+            RemoteStorageType::B2(c) => format!("b2://{}/{}", c.bucket, c.prefix),
+            RemoteStorageType::SymbolServer(c) => format!("symbolserver:{}", c.project),
+            RemoteStorageType::Path(c) => c.path.display().to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for RemoteStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            return write!(f, "'{name}'");
+        }
+        match &self.storage_type {
+            RemoteStorageType::SymbolServer(c) => write!(f, "symbolserver project '{}'", c.project),
+            _ => write!(f, "'{}'", self.location()),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
+            cache: None,
+            timeout: None,
             servers: default_servers(),
         }
     }
@@ -118,6 +257,25 @@ impl Config {
     pub fn from(path: &path::Path) -> std::io::Result<Self> {
         read_config(path)
     }
+
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.timeout.map(std::time::Duration::from_secs)
+    }
+
+    /// Returns the configured cache path, falling back to
+    /// `$XDG_CACHE_HOME/symbols` (or the OS equivalent).
+    pub fn cache_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.cache {
+            return Some(path.clone());
+        }
+
+        if let Some(dirs) = directories::ProjectDirs::from("", "", "symbols") {
+            Some(dirs.cache_dir().to_path_buf())
+        } else {
+            warn!("Unable to find OS cache path");
+            None
+        }
+    }
 }
 
 pub fn read_config(path: &path::Path) -> std::io::Result<Config> {
@@ -137,8 +295,13 @@ fn default_servers() -> std::vec::Vec<RemoteStorage> {
     vec![RemoteStorage {
         access: RemoteStorageAccess::Read,
         name: None,
+        retry: RetryConfig::default(),
+        layout: Layout::default(),
+        retention: None,
         storage_type: RemoteStorageType::Http(HttpConfig {
             url: "https://debuginfod.elfutils.org/".to_string(),
+            method: HttpMethod::default(),
+            auth: None,
         }),
     }]
 }
@@ -0,0 +1,478 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use symbolic_common::{Arch, CodeId, DebugId};
+use tokio::runtime::Runtime;
+
+use crate::cache::Cache;
+use crate::config;
+use crate::http;
+use crate::output::{self, Event};
+use crate::retry::{self, ErrorKind};
+use crate::s3;
+use crate::symsrv;
+use crate::symstore::file::{FileType, ResourceType};
+use crate::symstore::layout::{KeyParts, Layout};
+
+/// Determines what to look up on the servers for the given identifier.
+///
+/// Unless `file_type` is given, the identifier is treated as an ELF build-id
+/// without a filename, as in the debuginfod layout. With a filename it is
+/// treated as in the SymSrv layout, in which case a `.pdb` filename expects a
+/// debug-id and anything else a code-id.
+pub fn lookup(
+    id: &str,
+    resource_type: ResourceType,
+    file_type: Option<FileType>,
+    filename: Option<&str>,
+    arch: Option<&str>,
+) -> Result<KeyParts> {
+    let arch = arch
+        .map(|arch| Arch::from_str(arch).map_err(|_| anyhow!("Unknown architecture '{}'", arch)))
+        .transpose()?;
+    let file_type = match (file_type, filename) {
+        (Some(file_type), _) => file_type,
+        (None, None) => FileType::Elf,
+        (None, Some(filename)) if filename.to_lowercase().ends_with(".pdb") => FileType::Pdb,
+        (None, Some(_)) => FileType::Pe,
+    };
+
+    let identifier = if file_type == FileType::Pdb {
+        let debug_id = DebugId::from_str(id).map_err(|_| anyhow!("Invalid debug-id '{}'", id))?;
+        format!(
+            "{sig:X}{age:X}",
+            sig = debug_id.uuid().as_simple(),
+            age = debug_id.appendix()
+        )
+    } else {
+        parse_code_id(id)?.to_string()
+    };
+
+    let filename = filename.map(|filename| match resource_type {
+        ResourceType::SourceBundle => format!("{filename}.src.zip"),
+        _ => filename.to_string(),
+    });
+    Ok(KeyParts {
+        file_type,
+        resource_type,
+        identifier,
+        filename,
+        arch,
+    })
+}
+
+fn parse_code_id(id: &str) -> Result<CodeId> {
+    let code_id = CodeId::new(id.to_string());
+    if code_id.is_nil() {
+        return Err(anyhow!("Invalid identifier '{}'", id));
+    }
+    Ok(code_id)
+}
+
+/// Downloads the file described by `parts` and writes it to `output`. The
+/// cache is consulted first, then the servers in the order in which they are
+/// configured.
+pub fn download(
+    servers: &[config::RemoteStorage],
+    cache: &Cache,
+    parts: &KeyParts,
+    output: &Path,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<()> {
+    let key = &Layout::Default.key(parts)?;
+    let cached = fetch_cached_file(servers, cache, parts, timeout, rt)?;
+    std::fs::copy(&cached, output).context(format!(
+        "Failed to copy '{}' to '{}'",
+        cached.display(),
+        output.display()
+    ))?;
+    output::emit(
+        &Event::Download { key, path: output },
+        format_args!("Downloaded '{}' to '{}'", key, output.display()),
+    );
+    Ok(())
+}
+
+/// Returns the path of `key` in the cache, downloading it from the first
+/// server which has it if it is not cached yet. The key is the same on every
+/// server, whatever its layout.
+pub fn fetch_cached(
+    servers: &[config::RemoteStorage],
+    cache: &Cache,
+    key: &str,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<PathBuf> {
+    fetch_cached_with(servers, cache, key, timeout, rt, |_| Ok(key.to_string()))
+}
+
+/// Returns the path of the file described by `parts` in the cache,
+/// downloading it from the first server which has it if it is not cached yet.
+/// The file is looked up under its key in the layout of each server, and is
+/// cached under its key in the default layout.
+pub fn fetch_cached_file(
+    servers: &[config::RemoteStorage],
+    cache: &Cache,
+    parts: &KeyParts,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<PathBuf> {
+    let key = Layout::Default.key(parts)?;
+    fetch_cached_with(servers, cache, &key, timeout, rt, |server| {
+        server.layout.key(parts)
+    })
+}
+
+fn fetch_cached_with<F>(
+    servers: &[config::RemoteStorage],
+    cache: &Cache,
+    key: &str,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+    server_key: F,
+) -> Result<PathBuf>
+where
+    F: Fn(&config::RemoteStorage) -> Result<String>,
+{
+    if let Some(path) = cache.get(key) {
+        info!("Found '{}' in cache", key);
+        return Ok(path);
+    }
+
+    if servers.is_empty() {
+        return Err(anyhow!("No servers specified in config for download"));
+    }
+
+    for server in servers {
+        let server_key = match server_key(server) {
+            Ok(server_key) => server_key,
+            Err(error) => {
+                warn!("Unable to look for '{}' on {}: {}", key, server, error);
+                continue;
+            }
+        };
+        info!("Looking for '{}' on {}", server_key, server);
+        match cache.insert_with(key, |dest| {
+            fetch_symsrv(server, &server_key, dest, timeout, rt)
+        }) {
+            Ok(Some(path)) => {
+                info!("Downloaded '{}' from {}", server_key, server);
+                return Ok(path);
+            }
+            Ok(None) => info!("'{}' not found on {}", server_key, server),
+            Err(error) => warn!(
+                "Failed to download '{}' from {}: {:?}",
+                server_key, server, error
+            ),
+        }
+    }
+
+    Err(anyhow!("Unable to find '{}' on any configured server", key))
+}
+
+/// Fetches `key` from `server` into `dest`, falling back to the compressed
+/// file and then the `file.ptr` redirect for keys in the SymSrv layout.
+fn fetch_symsrv(
+    server: &config::RemoteStorage,
+    key: &str,
+    dest: &Path,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<bool> {
+    if fetch(server, key, dest, timeout, rt)? {
+        return Ok(true);
+    }
+
+    let Some((dir, filename)) = symsrv::split_key(key) else {
+        return Ok(false);
+    };
+
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = dest.with_file_name(name);
+
+    let compressed = format!("{}/{}", dir, symsrv::compressed_name(filename));
+    info!("Looking for '{}' on {}", compressed, server);
+    let found = fetch(server, &compressed, &tmp, timeout, rt);
+    let found = found.and_then(|found| {
+        if found {
+            symsrv::decompress(&tmp, dest)?;
+        }
+        Ok(found)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    if found? {
+        return Ok(true);
+    }
+
+    let ptr = format!("{dir}/file.ptr");
+    info!("Looking for '{}' on {}", ptr, server);
+    let found = fetch(server, &ptr, &tmp, timeout, rt);
+    let contents = found.and_then(|found| {
+        if found {
+            Ok(Some(std::fs::read_to_string(&tmp)?))
+        } else {
+            Ok(None)
+        }
+    });
+    let _ = std::fs::remove_file(&tmp);
+    let Some(contents) = contents? else {
+        return Ok(false);
+    };
+
+    match symsrv::parse_file_ptr(&contents)? {
+        symsrv::FilePtr::Msg(msg) => {
+            info!("'{}' is not available on {}: {}", key, server, msg);
+            Ok(false)
+        }
+        symsrv::FilePtr::Path(path) => {
+            info!("'{}' on {} redirects to '{}'", key, server, path);
+            fetch_ptr_path(server, &path, dest, timeout)
+        }
+    }
+}
+
+/// Fetches the target of a `file.ptr` redirect, which is either a URL or a
+/// path to a file. Paths are only followed for path stores, and only to files
+/// inside the store, so that a remote server cannot redirect to local files.
+fn fetch_ptr_path(
+    server: &config::RemoteStorage,
+    path: &str,
+    dest: &Path,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .build()?;
+        return get_to_file(client.get(path), dest);
+    }
+
+    let config::RemoteStorageType::Path(c) = &server.storage_type else {
+        warn!(
+            "Ignoring redirect of {} to the local path '{}'",
+            server, path
+        );
+        return Ok(false);
+    };
+    let Ok(src) = c.path.join(path).canonicalize() else {
+        return Ok(false);
+    };
+    let root = c
+        .path
+        .canonicalize()
+        .context(format!("Failed to resolve '{}'", c.path.display()))?;
+    if !src.starts_with(&root) {
+        warn!(
+            "Ignoring redirect of {} to '{}', which is outside of the store",
+            server, path
+        );
+        return Ok(false);
+    }
+    if !src.is_file() {
+        return Ok(false);
+    }
+    std::fs::copy(&src, dest).context(format!(
+        "Failed to copy '{}' to '{}'",
+        src.display(),
+        dest.display()
+    ))?;
+    Ok(true)
+}
+
+/// Fetches `key` from `server` into `dest`, retrying according to the retry
+/// policy of the server. Returns false if the server does not have the key.
+fn fetch(
+    server: &config::RemoteStorage,
+    key: &str,
+    dest: &Path,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<bool> {
+    let what = format!("Download of '{key}' from {server}");
+    retry::retry(&server.retry, &what, || {
+        fetch_once(server, key, dest, timeout, rt)
+    })
+}
+
+fn fetch_once(
+    server: &config::RemoteStorage,
+    key: &str,
+    dest: &Path,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<bool> {
+    match &server.storage_type {
+        config::RemoteStorageType::Http(c) => fetch_from_http(c, key, dest, timeout),
+        config::RemoteStorageType::S3(c) => rt.block_on(async {
+            let client = s3::s3_client(c, timeout).await;
+            fetch_from_s3(&client, &c.bucket, &format!("{}{}", c.prefix, key), dest).await
+        }),
+        config::RemoteStorageType::B2(c) => rt.block_on(async {
+            let client = s3::b2_client(c, timeout).await?;
+            fetch_from_s3(&client, &c.bucket, &format!("{}{}", c.prefix, key), dest).await
+        }),
+        config::RemoteStorageType::SymbolServer(_) => {
+            info!("Download from symbolserver is not supported");
+            Ok(false)
+        }
+        config::RemoteStorageType::Path(c) => {
+            let src = c.path.join(symsrv::store_path(key, symsrv::is_two_tier(c)));
+            if !src.is_file() {
+                return Ok(false);
+            }
+            std::fs::copy(&src, dest).context(format!(
+                "Failed to copy '{}' to '{}'",
+                src.display(),
+                dest.display()
+            ))?;
+            Ok(true)
+        }
+    }
+}
+
+fn fetch_from_http(
+    config: &config::HttpConfig,
+    key: &str,
+    dest: &Path,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    let url = http::url(config, key)?;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .build()?;
+    get_to_file(http::authorize(config, client.get(url))?, dest)
+}
+
+/// Sends `request` and writes the response body to `dest`. Returns false if
+/// the server responds with 404.
+fn get_to_file(request: reqwest::blocking::RequestBuilder, dest: &Path) -> Result<bool> {
+    let res = request.send()?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    let mut res = res.error_for_status()?;
+    let url = res.url().to_string();
+    let mut f =
+        std::fs::File::create(dest).context(format!("Failed to create '{}'", dest.display()))?;
+    res.copy_to(&mut f)
+        .context(format!("Failed to download '{}'", url))?;
+    Ok(true)
+}
+
+async fn fetch_from_s3(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    dest: &Path,
+) -> Result<bool> {
+    let object = match client.get_object().bucket(bucket).key(key).send().await {
+        Ok(object) => object,
+        Err(error) => {
+            if error
+                .as_service_error()
+                .map(|e| e.is_no_such_key())
+                .unwrap_or(false)
+            {
+                return Ok(false);
+            }
+            return Err(s3::sdk_error(error))
+                .context(format!("Failed to get '{}' from bucket '{}'", key, bucket));
+        }
+    };
+
+    let mut f =
+        std::fs::File::create(dest).context(format!("Failed to create '{}'", dest.display()))?;
+    let mut body = object.body;
+    while let Some(bytes) = body
+        .try_next()
+        .await
+        .map_err(|error| retry::classified(ErrorKind::Io, error))
+        .context(format!("Failed to download '{}'", key))?
+    {
+        f.write_all(&bytes)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_store(path: &Path) -> config::RemoteStorage {
+        config::RemoteStorage {
+            access: config::RemoteStorageAccess::Read,
+            name: None,
+            retry: config::RetryConfig::default(),
+            layout: Layout::default(),
+            retention: None,
+            storage_type: config::RemoteStorageType::Path(config::PathConfig {
+                path: path.to_path_buf(),
+                two_tier: false,
+            }),
+        }
+    }
+
+    #[test]
+    fn file_ptr_paths_stay_inside_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+        std::fs::create_dir_all(root.join("a.pdb/1")).unwrap();
+        std::fs::write(root.join("a.pdb/1/a.pdb"), "inside").unwrap();
+        std::fs::write(dir.path().join("secret"), "outside").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("../secret", root.join("link")).unwrap();
+        let server = path_store(&root);
+        let dest = dir.path().join("dest");
+
+        let inside = root.join("a.pdb/1/a.pdb");
+        for path in [
+            "a.pdb/1/a.pdb",
+            "a.pdb//1/./a.pdb",
+            inside.to_str().unwrap(),
+        ] {
+            assert!(
+                fetch_ptr_path(&server, path, &dest, None).unwrap(),
+                "{}",
+                path
+            );
+            assert_eq!(std::fs::read_to_string(&dest).unwrap(), "inside");
+            std::fs::remove_file(&dest).unwrap();
+        }
+
+        let outside = dir.path().join("secret");
+        for path in [
+            "",
+            "a.pdb",
+            "missing",
+            "../secret",
+            "a.pdb/../../secret",
+            "a.pdb\\..\\..\\secret",
+            "..\\secret",
+            outside.to_str().unwrap(),
+            "link",
+        ] {
+            assert!(
+                !fetch_ptr_path(&server, path, &dest, None).unwrap(),
+                "{}",
+                path
+            );
+            assert!(!dest.exists(), "{}", path);
+        }
+
+        // Only path stores follow redirects to paths
+        let mut server = server;
+        server.storage_type = config::RemoteStorageType::Http(config::HttpConfig {
+            url: "http://127.0.0.1:1/".to_string(),
+            method: Default::default(),
+            auth: None,
+        });
+        assert!(!fetch_ptr_path(&server, inside.to_str().unwrap(), &dest, None).unwrap());
+        assert!(!dest.exists());
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::blocking::RequestBuilder;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+
+use crate::config;
+use crate::retry::{self, ErrorKind};
+
+/// Returns the URL of `key` on the server. Each component of the key is
+/// percent-encoded, as keys of source files may contain any character.
+pub fn url(config: &config::HttpConfig, key: &str) -> Result<url::Url> {
+    let mut url =
+        url::Url::parse(&config.url).context(format!("Invalid server URL '{}'", config.url))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid server URL '{}'", config.url))?
+        .pop_if_empty()
+        .extend(key.split('/'));
+    Ok(url)
+}
+
+/// Returns the `Authorization` header for the configured authentication.
+/// Secrets are read from the environment each time this is called.
+pub fn authorization(config: &config::HttpConfig) -> Result<Option<HeaderValue>> {
+    let value = match &config.auth {
+        None => return Ok(None),
+        Some(config::HttpAuth::Basic {
+            username,
+            password_env,
+        }) => {
+            let password = match password_env {
+                Some(var) => std::env::var(var)
+                    .context(format!("Environment variable '{var}' is not set"))?,
+                None => String::new(),
+            };
+            let credentials = format!("{username}:{password}");
+            format!(
+                "Basic {}",
+                data_encoding::BASE64.encode(credentials.as_bytes())
+            )
+        }
+        Some(config::HttpAuth::Bearer { token_env }) => {
+            let token = std::env::var(token_env)
+                .context(format!("Environment variable '{token_env}' is not set"))?;
+            format!("Bearer {token}")
+        }
+    };
+    let mut value = HeaderValue::from_str(&value).context("Invalid credentials")?;
+    value.set_sensitive(true);
+    Ok(Some(value))
+}
+
+/// Adds the configured authentication to `request`.
+pub fn authorize(config: &config::HttpConfig, request: RequestBuilder) -> Result<RequestBuilder> {
+    Ok(match authorization(config)? {
+        Some(value) => request.header(AUTHORIZATION, value),
+        None => request,
+    })
+}
+
+/// Returns an async client whose requests time out after `timeout`.
+pub fn client(timeout: Option<Duration>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    Ok(builder.build()?)
+}
+
+/// Returns `res` if it succeeded, or an error with `message` and the body of
+/// the response otherwise.
+pub async fn success(res: reqwest::Response, message: &str) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let text = res.text().await.unwrap_or_default();
+    Err(retry::classified(
+        ErrorKind::Status(status.as_u16()),
+        format!("{message}. {text}"),
+    ))
+}
@@ -2,6 +2,8 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use strum::EnumString;
 
+use crate::output::{self, Event};
+
 use std::io::Write;
 use std::str::FromStr;
 
@@ -107,6 +109,9 @@ pub fn symbolserver_login() -> Result<()> {
     let token = rpassword::prompt_password("Enter symbolserver.com API token: ")?;
     let entry = keyring::Entry::new(SERVICE, USERNAME)?;
     entry.set_password(&token)?;
+    output::json(&Event::Login {
+        service: "symbolserver",
+    });
 
     Ok(())
 }
@@ -117,12 +122,13 @@ pub fn github_login() -> Result<()> {
     let client = reqwest::blocking::Client::new();
 
     let codes = request_device_and_user_verification_codes(&client)?;
-    prompt_user_to_copy_code(&codes.user_code)?;
+    prompt_user_to_copy_code(&codes.user_code, &codes.verification_uri)?;
     open_browser(&codes.verification_uri);
     let token = poll_for_token(&client, codes.device_code, codes.interval)?;
 
     let entry = keyring::Entry::new(SERVICE, USERNAME)?;
     entry.set_password(&token)?;
+    output::json(&Event::Login { service: "github" });
 
     Ok(())
 }
@@ -147,7 +153,16 @@ fn request_device_and_user_verification_codes(
     Ok(res)
 }
 
-fn prompt_user_to_copy_code(code: &str) -> Result<()> {
+fn prompt_user_to_copy_code(code: &str, verification_uri: &str) -> Result<()> {
+    // Scripts get the code right away since they cannot press ENTER
+    if output::format() == output::Format::Json {
+        output::json(&Event::LoginCode {
+            code,
+            verification_uri,
+        });
+        return Ok(());
+    }
+
     println!("Attempting to authenticate with GitHub...");
     println!("  1. Copy your one time code: {code}");
     print!("  2. Press ENTER to open up web browser to paste code...");
@@ -159,11 +174,13 @@ fn prompt_user_to_copy_code(code: &str) -> Result<()> {
 fn open_browser(url: &str) {
     match open::that(url) {
         Ok(_status) => {
-            println!("Opened GitHub Login in web browser");
+            output::text("Opened GitHub Login in web browser");
         }
         Err(e) => {
-            println!("Error opening web browser: {e}");
-            println!("Please click here to manually open web browser: {url}");
+            output::text(format_args!("Error opening web browser: {e}"));
+            output::text(format_args!(
+                "Please click here to manually open web browser: {url}"
+            ));
         }
     }
 }
@@ -219,7 +236,7 @@ fn poll_for_token(
             }
         } else {
             let access_token_response: GitHubAccesCheckResponse = serde_json::from_value(res)?;
-            println!("GitHub Login Successful");
+            output::text("GitHub Login Successful");
             break access_token_response.access_token;
         }
     };
@@ -4,11 +4,25 @@ use log::{/*error,*/ /*debug,*/ info, trace /*warn */};
 use std::{path::Path, path::PathBuf};
 
 use crate::config::{PathConfig, RemoteStorage, RemoteStorageType, S3Config};
+use crate::symstore::file::{FileType, ResourceType};
+use crate::symstore::layout::Layout;
 
 mod args;
+mod cache;
 mod config;
+mod download;
+mod http;
 mod login;
+mod manifest;
+mod output;
+mod prune;
+mod retry;
+mod s3;
+mod serve;
+mod source;
+mod symsrv;
 mod symstore;
+mod transaction;
 mod upload;
 
 fn main() -> Result<()> {
@@ -16,7 +30,27 @@ fn main() -> Result<()> {
     initialize_logger(&matches);
     trace!("logger initialized");
 
-    let config = if let Some(path) = matches.get_one::<String>(args::CONFIG_FILE_ARG) {
+    let format = match matches
+        .get_one::<String>(args::OUTPUT_FORMAT_ARG)
+        .map(|s| s.as_str())
+    {
+        Some("json") => output::Format::Json,
+        _ => output::Format::Text,
+    };
+    output::init(format);
+
+    let result = run(&matches);
+    if let Err(error) = &result {
+        if format == output::Format::Json {
+            output::json(&output::Event::error(error));
+            std::process::exit(1);
+        }
+    }
+    result
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<()> {
+    let mut config = if let Some(path) = matches.get_one::<String>(args::CONFIG_FILE_ARG) {
         let path = PathBuf::from(path);
         config::Config::from(&path)
             .context(format!("Failed to read config from '{}'", path.display()))?
@@ -24,6 +58,16 @@ fn main() -> Result<()> {
         config::Config::init().context("Failed to read default config")?
     };
 
+    if let Some(cache_path) = matches.get_one::<String>(args::CACHE_PATH_ARG) {
+        config.cache = Some(PathBuf::from(cache_path));
+    }
+    if let Some(timeout) = matches.get_one::<u64>(args::TIMEOUT_ARG) {
+        config.timeout = Some(*timeout);
+    }
+    let timeout = config.timeout();
+    // Shared by every backend, whichever subcommand runs
+    let rt = tokio::runtime::Runtime::new().context("Failed to start async runtime")?;
+
     if let Some(matches) = matches.subcommand_matches(args::UPLOAD_SUBCOMMAND) {
         info!("Upload subcommand");
         let search_path = Path::new(
@@ -31,8 +75,8 @@ fn main() -> Result<()> {
                 .get_one::<String>(args::UPLOAD_PATH_ARG)
                 .context("Unable to find upload path argument")?,
         );
-        let recursive_search = matches.contains_id(args::UPLOAD_RECUSRIVE_ARG);
-        let dryrun = matches.contains_id(args::UPLOAD_DRY_RUN_ARG);
+        let recursive_search = matches.get_flag(args::UPLOAD_RECUSRIVE_ARG);
+        let dryrun = matches.get_flag(args::UPLOAD_DRY_RUN_ARG);
         let mut writable_servers = config
             .servers
             .into_iter()
@@ -44,11 +88,15 @@ fn main() -> Result<()> {
             Some(RemoteStorage {
                 access: config::RemoteStorageAccess::ReadWrite,
                 name: None,
+                retry: config::RetryConfig::default(),
+                layout: Layout::default(),
+                retention: None,
                 storage_type: RemoteStorageType::S3(S3Config {
                     bucket: bucket.to_string(),
                     region: region.to_string(),
                     prefix: "".to_string(),
                     profile: None,
+                    part_size_mib: None,
                 }),
             })
         } else if let Some(output_dir) = matches.get_one::<String>(args::UPLOAD_OUTPUT_DIR_ARG) {
@@ -62,8 +110,12 @@ fn main() -> Result<()> {
             Some(RemoteStorage {
                 access: config::RemoteStorageAccess::ReadWrite,
                 name: None,
+                retry: config::RetryConfig::default(),
+                layout: Layout::default(),
+                retention: None,
                 storage_type: RemoteStorageType::Path(PathConfig {
                     path: output_dir.to_path_buf(),
+                    two_tier: false,
                 }),
             })
         } else if let Some(name) = matches.get_one::<String>(args::UPLOAD_SERVER_NAME_ARG) {
@@ -72,10 +124,165 @@ fn main() -> Result<()> {
             writable_servers.next()
         };
         if let Some(server) = server {
-            upload::upload(search_path, recursive_search, &server, dryrun)
+            let options = upload::UploadOptions {
+                recursive: recursive_search,
+                dryrun,
+                timeout,
+                with_sources: matches.get_flag(args::UPLOAD_WITH_SOURCES_ARG),
+                decompress_debug: matches.get_flag(args::UPLOAD_DECOMPRESS_DEBUG_ARG),
+                assemble_dwp: matches.get_flag(args::UPLOAD_ASSEMBLE_DWP_ARG),
+                format: match matches
+                    .get_one::<String>(args::UPLOAD_FORMAT_ARG)
+                    .map(|s| s.as_str())
+                {
+                    Some("breakpad") => upload::UploadFormat::Breakpad,
+                    Some("all") => upload::UploadFormat::All,
+                    _ => upload::UploadFormat::Native,
+                },
+                fat_macho: match matches
+                    .get_one::<String>(args::UPLOAD_FAT_MACHO_ARG)
+                    .map(|s| s.as_str())
+                {
+                    Some("container") => upload::FatMachO::Container,
+                    _ => upload::FatMachO::Slices,
+                },
+                archives: match matches
+                    .get_one::<String>(args::UPLOAD_ARCHIVES_ARG)
+                    .map(|s| s.as_str())
+                {
+                    Some("members") => upload::Archives::Members,
+                    Some("container") => upload::Archives::Container,
+                    _ => upload::Archives::Skip,
+                },
+                jobs: *matches.get_one::<u16>(args::UPLOAD_JOBS_ARG).unwrap() as usize,
+                manifest: matches
+                    .get_one::<String>(args::UPLOAD_MANIFEST_ARG)
+                    .map(PathBuf::from),
+                transaction: transaction::TransactionInfo {
+                    product: arg_or_default(matches, args::UPLOAD_PRODUCT_ARG),
+                    version: arg_or_default(matches, args::UPLOAD_PRODUCT_VERSION_ARG),
+                    comment: arg_or_default(matches, args::UPLOAD_COMMENT_ARG),
+                },
+            };
+            options.transaction.validate()?;
+            upload::upload(search_path, &server, &options, &rt)
         } else {
             Err(anyhow!("No server specified in config for upload"))
         }
+    } else if let Some(matches) = matches.subcommand_matches(args::DOWNLOAD_SUBCOMMAND) {
+        info!("Download subcommand");
+        let id = matches.get_one::<String>(args::DOWNLOAD_ID_ARG).unwrap();
+        let resource_type = if matches.get_flag(args::DOWNLOAD_EXECUTABLE_ARG) {
+            ResourceType::Executable
+        } else if matches.get_flag(args::DOWNLOAD_SOURCE_BUNDLE_ARG) {
+            ResourceType::SourceBundle
+        } else if matches.get_flag(args::DOWNLOAD_MINI_DEBUG_INFO_ARG) {
+            ResourceType::MiniDebugInfo
+        } else if matches.get_flag(args::DOWNLOAD_DWP_ARG) {
+            ResourceType::Dwp
+        } else {
+            ResourceType::DebugInfo
+        };
+        let filename = matches
+            .get_one::<String>(args::DOWNLOAD_FILE_NAME_ARG)
+            .map(|s| s.as_str());
+        let file_type = match matches
+            .get_one::<String>(args::DOWNLOAD_TYPE_ARG)
+            .map(|s| s.as_str())
+        {
+            Some("elf") => Some(FileType::Elf),
+            Some("macho") => Some(FileType::MachO),
+            Some("pe") => Some(FileType::Pe),
+            Some("pdb") => Some(FileType::Pdb),
+            _ => None,
+        };
+        let parts = download::lookup(
+            id,
+            resource_type,
+            file_type,
+            filename,
+            matches
+                .get_one::<String>(args::DOWNLOAD_ARCH_ARG)
+                .map(|s| s.as_str()),
+        )?;
+        let output = match matches.get_one::<String>(args::DOWNLOAD_OUTPUT_ARG) {
+            Some(output) => PathBuf::from(output),
+            None => match filename {
+                Some(filename) => match resource_type {
+                    ResourceType::SourceBundle => PathBuf::from(format!("{filename}.src.zip")),
+                    _ => PathBuf::from(filename),
+                },
+                None => match resource_type {
+                    ResourceType::Executable => PathBuf::from(format!("{id}.executable")),
+                    ResourceType::DebugInfo => PathBuf::from(format!("{id}.debuginfo")),
+                    ResourceType::SourceBundle => PathBuf::from(format!("{id}.sourcebundle")),
+                    ResourceType::MiniDebugInfo => PathBuf::from(format!("{id}.minidebuginfo")),
+                    ResourceType::Dwp => PathBuf::from(format!("{id}.dwp")),
+                },
+            },
+        };
+        let cache_path = config
+            .cache_path()
+            .context("Unable to determine cache path")?;
+        let cache = cache::Cache::new(&cache_path);
+        let servers = servers_by_name(
+            config.servers,
+            matches.get_one::<String>(args::DOWNLOAD_SERVER_NAME_ARG),
+        );
+        download::download(&servers, &cache, &parts, &output, timeout, &rt)
+    } else if let Some(matches) = matches.subcommand_matches(args::SOURCE_SUBCOMMAND) {
+        info!("Source subcommand");
+        let cache_path = config
+            .cache_path()
+            .context("Unable to determine cache path")?;
+        let cache = cache::Cache::new(&cache_path);
+        let servers = servers_by_name(
+            config.servers,
+            matches.get_one::<String>(args::SOURCE_SERVER_NAME_ARG),
+        );
+        if let Some(object) = matches.get_one::<String>(args::SOURCE_OBJECT_ARG) {
+            source::sources_for_object(&servers, &cache, Path::new(object), timeout, &rt)
+        } else {
+            let id = matches.get_one::<String>(args::SOURCE_ID_ARG).unwrap();
+            let path = matches.get_one::<String>(args::SOURCE_PATH_ARG).unwrap();
+            let output = matches
+                .get_one::<String>(args::SOURCE_OUTPUT_ARG)
+                .map(Path::new);
+            source::source(&servers, &cache, id, path, output, timeout, &rt)
+        }
+    } else if let Some(matches) = matches.subcommand_matches(args::SERVE_SUBCOMMAND) {
+        info!("Serve subcommand");
+        let listen = matches.get_one::<String>(args::SERVE_LISTEN_ARG).unwrap();
+        let path = matches
+            .get_one::<String>(args::SERVE_PATH_ARG)
+            .map(Path::new);
+        if let Some(path) = path {
+            if !path.is_dir() {
+                bail!("Specified path '{}' does not exist", path.display())
+            }
+        }
+        let cache = config.cache_path().map(|p| cache::Cache::new(&p));
+        let servers = if matches.get_flag(args::SERVE_LOCAL_ONLY_ARG) {
+            vec![]
+        } else {
+            config.servers
+        };
+        serve::serve(listen, path, servers, cache, timeout, &rt)
+    } else if let Some(matches) = matches.subcommand_matches(args::PRUNE_SUBCOMMAND) {
+        info!("Prune subcommand");
+        let name = matches
+            .get_one::<String>(args::PRUNE_SERVER_NAME_ARG)
+            .unwrap();
+        let dryrun = matches.get_flag(args::PRUNE_DRY_RUN_ARG);
+        let server = config
+            .servers
+            .iter()
+            .find(|server| server.name.as_ref() == Some(name))
+            .context(format!("No server named '{}' in config", name))?;
+        if server.access != config::RemoteStorageAccess::ReadWrite {
+            bail!("Server '{}' is read-only", name);
+        }
+        prune::prune(server, dryrun, timeout, &rt)
     } else if let Some(matches) = matches.subcommand_matches(args::LOGIN_SUBCOMMAND) {
         info!("Login subcommand");
         let service_name = matches.get_one::<String>(args::LOGIN_SERVICE_ARG).unwrap();
@@ -90,11 +297,34 @@ fn main() -> Result<()> {
     }
 }
 
+fn arg_or_default(matches: &clap::ArgMatches, id: &str) -> String {
+    matches.get_one::<String>(id).cloned().unwrap_or_default()
+}
+
+/// Returns only the server with the given name, or all servers if no name is
+/// given.
+fn servers_by_name(servers: Vec<RemoteStorage>, name: Option<&String>) -> Vec<RemoteStorage> {
+    match name {
+        Some(name) => servers
+            .into_iter()
+            .filter(|server| server.name.as_ref().unwrap_or(&"".to_owned()) == name)
+            .collect(),
+        None => servers,
+    }
+}
+
 fn initialize_logger(matches: &clap::ArgMatches) {
     // Vary the output based on how many times the user used the "verbose" flag
     // (i.e. 'myprog -v -v -v' or 'myprog -vvv' vs 'myprog -v'
     let mut logger = pretty_env_logger::formatted_builder();
-    let logger = match matches.get_count(args::VERBOSITY_ARG) {
+    let verbosity = match matches.get_count(args::VERBOSITY_ARG) {
+        0 => std::env::var(args::VERBOSITY_ENV)
+            .ok()
+            .and_then(|v| v.parse::<u8>().ok())
+            .unwrap_or(0),
+        count => count,
+    };
+    let logger = match verbosity {
         0 => logger.filter_level(log::LevelFilter::Error),
         1 => logger.filter_level(log::LevelFilter::Warn),
         2 => logger.filter_level(log::LevelFilter::Info),
@@ -0,0 +1,64 @@
+// The manifest written by `upload --manifest`, recording what happened to
+// each file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::symstore::file::{FileInfo, FileType, ResourceType};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Uploaded,
+    /// The key already exists on the server.
+    SkippedExisting,
+    /// Nothing was uploaded since this was a dry run.
+    DryRun,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    /// Path of the file which was found. For files generated from another
+    /// file, e.g. source bundles, this is the file they were generated from.
+    pub path: PathBuf,
+    pub file_type: FileType,
+    pub resource_type: ResourceType,
+    pub identifier: String,
+    pub key: String,
+    pub size: usize,
+    pub sha256: Option<String>,
+    /// Location of the server, e.g. its URL.
+    pub server: String,
+    pub outcome: Outcome,
+    /// Why the upload failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Entry {
+    pub fn new(file: &FileInfo, key: String, server: String, result: &Result<Outcome>) -> Self {
+        Self {
+            path: file.origin.clone(),
+            file_type: file.file_type,
+            resource_type: file.resource_type,
+            identifier: file.identifier.clone(),
+            key,
+            size: file.file_size,
+            sha256: None,
+            server,
+            outcome: *result.as_ref().unwrap_or(&Outcome::Failed),
+            reason: result.as_ref().err().map(|error| format!("{error:#}")),
+        }
+    }
+}
+
+pub fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .context(format!("Failed to create manifest '{}'", path.display()))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), entries)
+        .context(format!("Failed to write manifest '{}'", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,119 @@
+// Output of the results of commands, either as text for humans or as JSON
+// lines for scripts. Logs are written to stderr in both cases, so stdout only
+// ever holds results.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+
+use crate::manifest::Outcome;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+pub fn init(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> Format {
+    *FORMAT.get().unwrap_or(&Format::Text)
+}
+
+/// A result of a command, printed as a single JSON line in JSON mode.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    Upload {
+        path: &'a Path,
+        key: &'a str,
+        server: &'a str,
+        outcome: Outcome,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A member of the static library at `path` has no identifier, so it
+    /// cannot be uploaded.
+    UnidentifiedMember {
+        path: &'a Path,
+        member: &'a str,
+    },
+    /// The uploaded files were recorded as a symstore transaction of the
+    /// store at `server`.
+    Transaction {
+        id: &'a str,
+        server: &'a str,
+    },
+    /// A file added by transaction `id` was deleted, or would be deleted in a
+    /// dry run.
+    Prune {
+        id: &'a str,
+        product: &'a str,
+        version: &'a str,
+        path: &'a str,
+        dryrun: bool,
+    },
+    Download {
+        key: &'a str,
+        path: &'a Path,
+    },
+    /// A source file was downloaded, or could not be.
+    Source {
+        source: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<&'a Path>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Serve {
+        address: String,
+    },
+    /// The code to enter at `verification_uri` to log in.
+    LoginCode {
+        code: &'a str,
+        verification_uri: &'a str,
+    },
+    Login {
+        service: &'a str,
+    },
+    Error {
+        message: String,
+        causes: Vec<String>,
+    },
+}
+
+impl Event<'_> {
+    pub fn error(error: &anyhow::Error) -> Self {
+        Event::Error {
+            message: error.to_string(),
+            causes: error.chain().skip(1).map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+/// Prints `line` in text mode. Nothing is printed in JSON mode.
+pub fn text(line: impl Display) {
+    if format() == Format::Text {
+        println!("{line}");
+    }
+}
+
+/// Prints `event` in JSON mode. Nothing is printed in text mode.
+pub fn json(event: &Event) {
+    if format() == Format::Json {
+        // Serializing an event cannot fail
+        println!("{}", serde_json::to_string(event).unwrap());
+    }
+}
+
+/// Prints `line` in text mode or `event` in JSON mode.
+pub fn emit(event: &Event, line: impl Display) {
+    text(line);
+    json(event);
+}
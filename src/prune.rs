@@ -0,0 +1,398 @@
+// Deletion of old builds from a server according to its retention policy.
+// A build is a transaction recorded in the `000Admin` folder of the store by
+// an upload, and only the files recorded by a transaction are deleted, so
+// files which were not uploaded by a recorded transaction are never deleted.
+// symbolserver records the uploads to a project itself, and applies the
+// policy on request.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::config::{RemoteStorage, RemoteStorageType, RetentionConfig, SymbolServerConfig};
+use crate::http;
+use crate::output::{self, Event};
+use crate::retry;
+use crate::transaction::{self, Added, Transaction};
+
+/// The retention policy of a symbolserver project, which the server applies
+/// to the builds recorded by uploads.
+#[derive(Serialize)]
+struct SymbolServerPruneRequest<'a> {
+    max_age_days: Option<u64>,
+    keep_last: Option<usize>,
+    keep_versions: &'a [String],
+    dryrun: bool,
+}
+
+/// A file which symbolserver deleted, or would delete in a dry run.
+#[derive(Deserialize)]
+struct SymbolServerPrunedFile {
+    /// The upload which added the file.
+    id: String,
+    product: String,
+    version: String,
+    path: String,
+}
+
+pub fn prune(
+    server: &RemoteStorage,
+    dryrun: bool,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<()> {
+    if let RemoteStorageType::Http(_) = &server.storage_type {
+        bail!("Pruning is not supported for HTTP servers, which cannot delete files")
+    }
+    let retention = server
+        .retention
+        .as_ref()
+        .ok_or_else(|| anyhow!("No retention policy configured for {}", server))?;
+    if retention.max_age_days.is_none() && retention.keep_last.is_none() {
+        bail!(
+            "The retention policy of {} needs max_age_days or keep_last",
+            server
+        );
+    }
+
+    if let RemoteStorageType::SymbolServer(c) = &server.storage_type {
+        return prune_symbolserver(c, server, retention, dryrun, timeout, rt);
+    }
+
+    let store = transaction::store(server, timeout, rt)?;
+    let transactions = transaction::list(&*store)?;
+    let mut added = HashMap::new();
+    for t in &transactions {
+        added.insert(t.id.as_str(), transaction::added(&*store, &t.id)?);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // Transactions which only record their folders are never pruned, as the
+    // folders may hold files of other builds
+    let expired = expired(&transactions, retention, now)
+        .into_iter()
+        .filter(|t| {
+            let files_only = added[t.id.as_str()]
+                .iter()
+                .all(|a| matches!(a, Added::File(_)));
+            if !files_only {
+                warn!(
+                    "Keeping transaction {}, which does not record its files",
+                    t.id
+                );
+            }
+            files_only
+        })
+        .collect::<Vec<_>>();
+    let expired_ids = expired
+        .iter()
+        .map(|t| t.id.as_str())
+        .collect::<HashSet<_>>();
+
+    // A file may belong to several builds, when a later build uploaded an
+    // unchanged file, so files of kept builds are not deleted.
+    let mut kept_files = HashSet::new();
+    let mut kept_dirs = Vec::new();
+    for kept in transactions
+        .iter()
+        .filter(|t| !expired_ids.contains(t.id.as_str()))
+    {
+        for a in &added[kept.id.as_str()] {
+            match a {
+                Added::File(path) => kept_files.insert(path.as_str()),
+                Added::Dir(dir) => {
+                    kept_dirs.push(Path::new(dir));
+                    true
+                }
+            };
+        }
+    }
+    let is_kept = |path: &str| {
+        kept_files.contains(path) || kept_dirs.iter().any(|dir| Path::new(path).starts_with(dir))
+    };
+
+    let mut deleted = HashSet::new();
+    let mut pruned = 0;
+    for t in &expired {
+        let mut in_use = false;
+        for a in &added[t.id.as_str()] {
+            let Added::File(path) = a else {
+                continue;
+            };
+            if is_kept(path) {
+                info!("Keeping '{}', which belongs to a kept build", path);
+                in_use = true;
+                continue;
+            }
+            if !deleted.insert(path.as_str()) {
+                continue;
+            }
+            let event = Event::Prune {
+                id: &t.id,
+                product: &t.info.product,
+                version: &t.info.version,
+                path,
+                dryrun,
+            };
+            if dryrun {
+                output::emit(
+                    &event,
+                    format_args!(
+                        "Would delete '{}' of transaction {} ({} {})",
+                        path, t.id, t.info.product, t.info.version
+                    ),
+                );
+            } else if store.delete_file(path)? {
+                output::emit(
+                    &event,
+                    format_args!("Deleted '{}' of transaction {}", path, t.id),
+                );
+            } else {
+                info!("'{}' of transaction {} was already deleted", path, t.id);
+            }
+        }
+        // The transaction is only deleted once none of its files remain, so
+        // that the files kept for now are pruned with a later build
+        if in_use {
+            info!(
+                "Keeping transaction {}, whose files belong to kept builds",
+                t.id
+            );
+            continue;
+        }
+        pruned += 1;
+        if !dryrun {
+            let id = transaction::delete(&*store, &t.id)?;
+            info!("Recorded deletion of transaction {} as {}", t.id, id);
+        }
+    }
+
+    info!(
+        "{} {} of {} transactions from {}",
+        if dryrun { "Would prune" } else { "Pruned" },
+        pruned,
+        transactions.len(),
+        server
+    );
+    Ok(())
+}
+
+/// Asks symbolserver to apply `retention` to the uploads of the project, which
+/// it records along with their product and version, as it keeps no
+/// transactions in the project itself.
+fn prune_symbolserver(
+    config: &SymbolServerConfig,
+    server: &RemoteStorage,
+    retention: &RetentionConfig,
+    dryrun: bool,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<()> {
+    const SERVICE: &str = "com.symboserver.symbols";
+    const USERNAME: &str = "symbolserver";
+    let entry = keyring::Entry::new(SERVICE, USERNAME)?;
+    let token = &entry.get_password()?;
+    let client = &http::client(timeout)?;
+
+    let url = config
+        .url
+        .as_deref()
+        .unwrap_or("https://api.symbolserver.com");
+    let prune = format!("{}/symbols/{}/prune", url, config.project);
+    let request = SymbolServerPruneRequest {
+        max_age_days: retention.max_age_days,
+        keep_last: retention.keep_last,
+        keep_versions: &retention.keep_versions,
+        dryrun,
+    };
+    let pruned = rt.block_on(retry::retry_async(
+        &server.retry,
+        &format!("Request to '{prune}'"),
+        || async {
+            let res = client
+                .post(&prune)
+                .bearer_auth(token)
+                .json(&request)
+                .send()
+                .await?;
+            let res = http::success(res, "Pruning did not succeed").await?;
+            Ok(res.json::<Vec<SymbolServerPrunedFile>>().await?)
+        },
+    ))?;
+
+    for file in &pruned {
+        let event = Event::Prune {
+            id: &file.id,
+            product: &file.product,
+            version: &file.version,
+            path: &file.path,
+            dryrun,
+        };
+        if dryrun {
+            output::emit(
+                &event,
+                format_args!(
+                    "Would delete '{}' of upload {} ({} {})",
+                    file.path, file.id, file.product, file.version
+                ),
+            );
+        } else {
+            output::emit(
+                &event,
+                format_args!("Deleted '{}' of upload {}", file.path, file.id),
+            );
+        }
+    }
+    info!(
+        "{} {} files from {}",
+        if dryrun { "Would prune" } else { "Pruned" },
+        pruned.len(),
+        server
+    );
+    Ok(())
+}
+
+/// Returns the transactions which none of the rules of `retention` keeps,
+/// oldest first.
+fn expired<'a>(
+    transactions: &'a [Transaction],
+    retention: &RetentionConfig,
+    now: u64,
+) -> Vec<&'a Transaction> {
+    let mut builds = HashMap::new();
+    let mut expired = Vec::new();
+    // Newest first, so that the newest builds of each product are counted
+    for t in transactions.iter().rev() {
+        let count = builds.entry(t.info.product.as_str()).or_insert(0);
+        *count += 1;
+        if retention.keep_last.is_some_and(|n| *count <= n) {
+            continue;
+        }
+        if retention
+            .keep_versions
+            .iter()
+            .any(|pattern| matches_pattern(pattern, &t.info.version))
+        {
+            continue;
+        }
+        let old = match (retention.max_age_days, t.timestamp()) {
+            (None, _) => true,
+            (Some(days), Some(timestamp)) => now.saturating_sub(timestamp) > days * 86_400,
+            (Some(_), None) => {
+                warn!(
+                    "Keeping transaction {}, whose date '{} {}' is invalid",
+                    t.id, t.date, t.time
+                );
+                false
+            }
+        };
+        if old {
+            expired.push(t);
+        }
+    }
+    expired.reverse();
+    expired
+}
+
+/// Whether `text` matches `pattern`, in which `*` matches any characters and
+/// `?` any single character.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and of the text it was matched against
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionInfo;
+
+    const DAY: u64 = 86_400;
+
+    fn transaction(id: &str, date: &str, product: &str, version: &str) -> Transaction {
+        Transaction {
+            id: id.to_string(),
+            date: date.to_string(),
+            time: "12:00:00".to_string(),
+            info: TransactionInfo {
+                product: product.to_string(),
+                version: version.to_string(),
+                comment: String::new(),
+            },
+        }
+    }
+
+    fn ids(transactions: Vec<&Transaction>) -> Vec<&str> {
+        transactions.iter().map(|t| t.id.as_str()).collect()
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("v*", "v1.2.3"));
+        assert!(matches_pattern("v1.?", "v1.2"));
+        assert!(matches_pattern("*-rc*", "v1.0-rc2"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(!matches_pattern("v1.?", "v1.23"));
+        assert!(!matches_pattern("v*", "nightly"));
+        assert!(!matches_pattern("a*b", "ab-c"));
+        assert!(!matches_pattern("", "a"));
+    }
+
+    #[test]
+    fn keep_last_counts_each_product() {
+        let transactions = [
+            transaction("0000000001", "01/01/2026", "app", "1"),
+            transaction("0000000002", "01/02/2026", "lib", "1"),
+            transaction("0000000003", "01/03/2026", "app", "2"),
+            transaction("0000000004", "01/04/2026", "app", "3"),
+        ];
+        let retention = RetentionConfig {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ids(expired(&transactions, &retention, 0)), ["0000000001"]);
+    }
+
+    #[test]
+    fn max_age_and_kept_versions() {
+        let transactions = [
+            transaction("0000000001", "01/01/2026", "app", "v1.0"),
+            transaction("0000000002", "01/02/2026", "app", "nightly-1"),
+            transaction("0000000003", "01/30/2026", "app", "nightly-2"),
+            transaction("0000000004", "bogus", "app", "nightly-3"),
+        ];
+        let retention = RetentionConfig {
+            max_age_days: Some(10),
+            keep_versions: vec!["v*".to_string()],
+            ..Default::default()
+        };
+        let now = transactions[2].timestamp().unwrap() + DAY;
+        assert_eq!(ids(expired(&transactions, &retention, now)), ["0000000002"]);
+    }
+}
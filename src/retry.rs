@@ -0,0 +1,147 @@
+// Retrying of failed operations with a server according to its retry policy.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use rand::Rng;
+
+use crate::config::RetryConfig;
+
+/// How an error is treated by a retry policy.
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorKind {
+    /// The server responded with the given HTTP status.
+    Status(u16),
+    /// A transient I/O error, such as a timeout or a reset connection.
+    Io,
+    /// An error which will not go away by trying again.
+    Fatal,
+}
+
+/// An error along with how it is treated by a retry policy. Used for errors
+/// whose kind cannot be recovered from the error itself.
+#[derive(Debug)]
+pub struct Classified {
+    kind: ErrorKind,
+    error: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub fn classified(
+    kind: ErrorKind,
+    error: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> anyhow::Error {
+    anyhow::Error::new(Classified {
+        kind,
+        error: error.into(),
+    })
+}
+
+/// Returns how `error` is treated by a retry policy.
+pub fn kind(error: &anyhow::Error) -> ErrorKind {
+    for cause in error.chain() {
+        if let Some(classified) = cause.downcast_ref::<Classified>() {
+            return classified.kind;
+        }
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            if let Some(status) = error.status() {
+                return ErrorKind::Status(status.as_u16());
+            }
+            if error.is_timeout() || error.is_connect() || error.is_request() || error.is_body() {
+                return ErrorKind::Io;
+            }
+        }
+        if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            return match error.kind() {
+                TimedOut | Interrupted | WouldBlock | ConnectionReset | ConnectionAborted
+                | ConnectionRefused | BrokenPipe | UnexpectedEof => ErrorKind::Io,
+                _ => ErrorKind::Fatal,
+            };
+        }
+    }
+    ErrorKind::Fatal
+}
+
+impl RetryConfig {
+    fn should_retry(&self, error: &anyhow::Error) -> bool {
+        match kind(error) {
+            ErrorKind::Status(status) => self.retry_statuses.contains(&status),
+            ErrorKind::Io => self.retry_io_errors,
+            ErrorKind::Fatal => false,
+        }
+    }
+
+    /// Returns the delay before the given retry, which is the exponential
+    /// backoff with up to half of it taken off at random.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(rand::rng().random_range(backoff / 2..=backoff))
+    }
+
+    /// Decides whether to retry after `attempt` failed with `error`. Returns
+    /// the delay before the next attempt.
+    fn next(&self, what: &str, attempt: u32, error: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.should_retry(error) {
+            return None;
+        }
+        let delay = self.backoff(attempt - 1);
+        warn!(
+            "{} failed (attempt {} of {}), retrying in {:?}: {:#}",
+            what, attempt, self.max_attempts, delay, error
+        );
+        Some(delay)
+    }
+}
+
+/// Runs `op` until it succeeds, fails with an error which `policy` does not
+/// retry, or runs out of attempts.
+pub fn retry<T>(policy: &RetryConfig, what: &str, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(error) => match policy.next(what, attempt, &error) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(error),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// Async version of [`retry`].
+pub async fn retry_async<T, F, Fut>(policy: &RetryConfig, what: &str, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(error) => match policy.next(what, attempt, &error) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(error),
+            },
+        }
+        attempt += 1;
+    }
+}
@@ -0,0 +1,435 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::timeout::TimeoutConfig;
+use aws_config::Region;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use log::{trace, warn};
+
+use crate::config;
+use crate::retry::{self, ErrorKind};
+use crate::transaction;
+
+const MIB: u64 = 1024 * 1024;
+const DEFAULT_PART_SIZE: u64 = 64 * MIB;
+// Limits of S3 multipart uploads
+const MIN_PART_SIZE: u64 = 5 * MIB;
+const MAX_PART_SIZE: u64 = 5 * 1024 * MIB;
+const MAX_PARTS: u64 = 10_000;
+// Number of times a file of 000Admin is read and written again when it keeps
+// changing concurrently
+const MAX_UPDATE_ATTEMPTS: u32 = 10;
+
+pub async fn s3_client(config: &config::S3Config, timeout: Option<Duration>) -> aws_sdk_s3::Client {
+    let builder = aws_config::profile::ProfileFileCredentialsProvider::builder();
+    let builder = if let Some(profile) = &config.profile {
+        builder.profile_name(profile)
+    } else {
+        builder
+    };
+    let provider = builder.build();
+    let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .credentials_provider(provider)
+        .region(Region::new(config.region.clone()))
+        .timeout_config(timeout_config(timeout))
+        .retry_config(aws_config::retry::RetryConfig::disabled())
+        .load()
+        .await;
+    aws_sdk_s3::Client::new(&sdk_config)
+}
+
+pub async fn b2_client(
+    config: &config::B2Config,
+    timeout: Option<Duration>,
+) -> Result<aws_sdk_s3::Client> {
+    let b2_creds = match &config.account_id {
+        Some(id) => b2creds::Credentials::from_file(None, Some(id))?,
+        None => b2creds::Credentials::locate()?,
+    };
+
+    let creds = Credentials::new(
+        &b2_creds.application_key_id,
+        &b2_creds.application_key,
+        None,
+        None,
+        "b2",
+    );
+
+    let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .credentials_provider(creds)
+        .region("b2")
+        .endpoint_url(config.endpoint.clone())
+        .timeout_config(timeout_config(timeout))
+        .retry_config(aws_config::retry::RetryConfig::disabled())
+        .load()
+        .await;
+    Ok(aws_sdk_s3::Client::new(&sdk_config))
+}
+
+/// Converts an error of the SDK into an error which tells the retry policy
+/// whether it is worth retrying. Retries are left to the policy of the server
+/// rather than done by the SDK.
+pub fn sdk_error<E>(error: SdkError<E, HttpResponse>) -> anyhow::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let kind = match &error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            ErrorKind::Io
+        }
+        SdkError::ServiceError(e) => ErrorKind::Status(e.raw().status().as_u16()),
+        _ => ErrorKind::Fatal,
+    };
+    retry::classified(kind, error)
+}
+
+fn timeout_config(timeout: Option<Duration>) -> TimeoutConfig {
+    let builder = TimeoutConfig::builder();
+    match timeout {
+        Some(timeout) => builder.operation_attempt_timeout(timeout).build(),
+        None => builder.build(),
+    }
+}
+
+/// Uploads the file at `path` to `key`. Files larger than a part are sent with
+/// a multipart upload so that a failed part can be resent on its own. The
+/// upload is aborted if any part fails, so no incomplete upload is left behind.
+pub async fn put_file(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    path: &Path,
+    part_size_mib: Option<u64>,
+    policy: &config::RetryConfig,
+) -> Result<()> {
+    let len = tokio::fs::metadata(path)
+        .await
+        .context(format!("Failed to read metadata of '{}'", path.display()))?
+        .len();
+    let part_size = part_size(len, part_size_mib)?;
+    if len <= part_size {
+        return retry::retry_async(policy, &format!("Upload of '{key}'"), || async {
+            let body = ByteStream::from_path(path).await?;
+            client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(body)
+                .send()
+                .await
+                .map_err(sdk_error)?;
+            Ok(())
+        })
+        .await;
+    }
+
+    let upload = retry::retry_async(policy, &format!("Start of upload of '{key}'"), || async {
+        client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(sdk_error)
+    })
+    .await
+    .context("Failed to start multipart upload")?;
+    let upload_id = upload
+        .upload_id()
+        .ok_or_else(|| anyhow!("Multipart upload has no id"))?;
+
+    let result = put_parts(client, bucket, key, upload_id, path, len, part_size, policy).await;
+    if result.is_err() {
+        if let Err(error) = client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            warn!("Failed to abort multipart upload of '{key}': {error}");
+        }
+    }
+    result
+}
+
+/// Returns the part size to use for a file of `len` bytes, keeping within the
+/// limits of S3.
+fn part_size(len: u64, part_size_mib: Option<u64>) -> Result<u64> {
+    let part_size = match part_size_mib {
+        Some(size) => size
+            .checked_mul(MIB)
+            .ok_or_else(|| anyhow!("Invalid part_size_mib {size} in configuration"))?,
+        None => DEFAULT_PART_SIZE,
+    };
+    let part_size = part_size.clamp(MIN_PART_SIZE, MAX_PART_SIZE);
+    Ok(part_size.max(len.div_ceil(MAX_PARTS)))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn put_parts(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    path: &Path,
+    len: u64,
+    part_size: u64,
+    policy: &config::RetryConfig,
+) -> Result<()> {
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let part_number = parts.len() as i32 + 1;
+        let size = part_size.min(len - offset);
+        let what = format!("Upload of part {part_number} of '{key}'");
+        let output = retry::retry_async(policy, &what, || async {
+            trace!("Uploading part {part_number} of '{key}'");
+            let body = ByteStream::read_from()
+                .path(path)
+                .offset(offset)
+                .length(Length::Exact(size))
+                .build()
+                .await?;
+            client
+                .upload_part()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .content_length(size as i64)
+                .body(body)
+                .send()
+                .await
+                .map_err(sdk_error)
+        })
+        .await
+        .context(format!("Failed to upload part {part_number} of '{key}'"))?;
+        parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(output.e_tag().map(|e_tag| e_tag.to_string()))
+                .build(),
+        );
+        offset += size;
+    }
+
+    let parts = CompletedMultipartUpload::builder()
+        .set_parts(Some(parts))
+        .build();
+    retry::retry_async(
+        policy,
+        &format!("Completion of upload of '{key}'"),
+        || async {
+            client
+                .complete_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(parts.clone())
+                .send()
+                .await
+                .map_err(sdk_error)
+        },
+    )
+    .await
+    .context("Failed to complete multipart upload")?;
+    Ok(())
+}
+
+/// A store in an S3 or B2 bucket, whose `000Admin` folder is kept under the
+/// prefix of the bucket. The files of `000Admin` are written with conditional
+/// requests, so that concurrent uploads do not overwrite each other's records.
+pub struct Store {
+    rt: tokio::runtime::Handle,
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+    policy: config::RetryConfig,
+}
+
+impl Store {
+    pub fn new(
+        rt: tokio::runtime::Handle,
+        client: aws_sdk_s3::Client,
+        bucket: &str,
+        prefix: &str,
+        policy: &config::RetryConfig,
+    ) -> Self {
+        Self {
+            rt,
+            client,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            policy: policy.clone(),
+        }
+    }
+
+    fn admin_key(&self, name: &str) -> String {
+        format!("{}{}/{}", self.prefix, transaction::ADMIN_DIR, name)
+    }
+
+    /// Reads the object `key` along with its ETag.
+    async fn get(&self, key: &str) -> Result<Option<(String, Option<String>)>> {
+        retry::retry_async(&self.policy, &format!("Download of '{key}'"), || async {
+            let object = match self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await
+            {
+                Ok(object) => object,
+                Err(error) => {
+                    if error
+                        .as_service_error()
+                        .map(|e| e.is_no_such_key())
+                        .unwrap_or(false)
+                    {
+                        return Ok(None);
+                    }
+                    return Err(sdk_error(error));
+                }
+            };
+            let e_tag = object.e_tag().map(|e_tag| e_tag.to_string());
+            let body = object
+                .body
+                .collect()
+                .await
+                .map_err(|error| retry::classified(ErrorKind::Io, error))?;
+            let contents = String::from_utf8(body.to_vec())
+                .map_err(|_| anyhow!("'{}' is not valid UTF-8", key))?;
+            Ok(Some((contents, e_tag)))
+        })
+        .await
+        .context(format!(
+            "Failed to get '{}' from bucket '{}'",
+            key, self.bucket
+        ))
+    }
+
+    /// Writes `contents` to the object `key` if it still has the ETag
+    /// `e_tag`, or does not exist yet if `e_tag` is `None`. Returns false if
+    /// the object was changed in the meantime.
+    async fn put_if(&self, key: &str, contents: &str, e_tag: Option<&str>) -> Result<bool> {
+        let result = retry::retry_async(&self.policy, &format!("Upload of '{key}'"), || async {
+            let request = self
+                .client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(ByteStream::from(contents.as_bytes().to_vec()));
+            let request = match e_tag {
+                Some(e_tag) => request.if_match(e_tag),
+                None => request.if_none_match("*"),
+            };
+            request.send().await.map_err(sdk_error)?;
+            Ok(())
+        })
+        .await;
+        match result {
+            Ok(()) => Ok(true),
+            // 409 is returned while a conflicting conditional write is running
+            Err(error) if matches!(retry::kind(&error), ErrorKind::Status(409 | 412)) => Ok(false),
+            Err(error) => Err(error).context(format!(
+                "Failed to upload '{}' to bucket '{}'",
+                key, self.bucket
+            )),
+        }
+    }
+}
+
+impl transaction::Store for Store {
+    fn read(&self, name: &str) -> Result<Option<String>> {
+        let key = self.admin_key(name);
+        let object = self.rt.block_on(self.get(&key))?;
+        Ok(object.map(|(contents, _)| contents))
+    }
+
+    fn create(&self, name: &str, contents: &str) -> Result<bool> {
+        let key = self.admin_key(name);
+        self.rt.block_on(self.put_if(&key, contents, None))
+    }
+
+    fn update(&self, name: &str, f: &dyn Fn(&str) -> String) -> Result<()> {
+        let key = self.admin_key(name);
+        self.rt.block_on(async {
+            for _ in 0..MAX_UPDATE_ATTEMPTS {
+                let (contents, e_tag) = match self.get(&key).await? {
+                    Some((contents, Some(e_tag))) => (contents, Some(e_tag)),
+                    Some((_, None)) => bail!(
+                        "'{}' in bucket '{}' has no ETag and cannot be updated safely",
+                        key,
+                        self.bucket
+                    ),
+                    None => (String::new(), None),
+                };
+                if self.put_if(&key, &f(&contents), e_tag.as_deref()).await? {
+                    return Ok(());
+                }
+                trace!("'{key}' was changed concurrently, updating it again");
+            }
+            bail!(
+                "Failed to update '{}' in bucket '{}' after {} attempts, it kept changing concurrently",
+                key,
+                self.bucket,
+                MAX_UPDATE_ATTEMPTS
+            )
+        })
+    }
+
+    fn delete_file(&self, path: &str) -> Result<bool> {
+        let key = format!("{}{}", self.prefix, path);
+        self.rt.block_on(async {
+            // S3 deletes missing objects without an error, so look first
+            let exists =
+                retry::retry_async(&self.policy, &format!("Lookup of '{key}'"), || async {
+                    match self
+                        .client
+                        .head_object()
+                        .bucket(&self.bucket)
+                        .key(&key)
+                        .send()
+                        .await
+                    {
+                        Ok(_) => Ok(true),
+                        Err(error)
+                            if error
+                                .as_service_error()
+                                .map(|e| e.is_not_found())
+                                .unwrap_or(false) =>
+                        {
+                            Ok(false)
+                        }
+                        Err(error) => Err(sdk_error(error)),
+                    }
+                })
+                .await?;
+            if !exists {
+                return Ok(false);
+            }
+            retry::retry_async(&self.policy, &format!("Deletion of '{key}'"), || async {
+                self.client
+                    .delete_object()
+                    .bucket(&self.bucket)
+                    .key(&key)
+                    .send()
+                    .await
+                    .map_err(sdk_error)
+            })
+            .await
+            .context(format!(
+                "Failed to delete '{}' from bucket '{}'",
+                key, self.bucket
+            ))?;
+            Ok(true)
+        })
+    }
+}
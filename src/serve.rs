@@ -0,0 +1,214 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use symbolic_common::ByteView;
+use symbolic_debuginfo::sourcebundle::SourceBundle;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tokio::runtime::Runtime;
+
+use crate::cache::Cache;
+use crate::config;
+use crate::download;
+use crate::output::{self, Event};
+use crate::symsrv;
+use crate::symstore::file;
+use crate::symstore::layout::KeyParts;
+
+struct Store<'a> {
+    path: Option<PathBuf>,
+    servers: Vec<config::RemoteStorage>,
+    cache: Option<Cache>,
+    timeout: Option<Duration>,
+    rt: &'a Runtime,
+}
+
+/// Serves files over HTTP using the debuginfod protocol. Each request path is
+/// treated as a key of the default layout (e.g. `/buildid/<id>/debuginfo`)
+/// which is looked up in `path` first and then in the configured servers,
+/// under its key in the layout of each server. Source files
+/// (`/buildid/<id>/source/<path>`) which are not found are extracted from the
+/// source bundle of the build-id.
+pub fn serve(
+    listen: &str,
+    path: Option<&Path>,
+    servers: Vec<config::RemoteStorage>,
+    cache: Option<Cache>,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<()> {
+    let server = Server::http(listen).map_err(|e| anyhow!("Failed to listen on {listen}: {e}"))?;
+    let store = Store {
+        path: path.map(|p| p.to_path_buf()),
+        servers,
+        cache,
+        timeout,
+        rt,
+    };
+    let address = format!("http://{}", server.server_addr());
+    output::emit(
+        &Event::Serve {
+            address: address.clone(),
+        },
+        format_args!("Serving symbols on {address}"),
+    );
+
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    handle(&store, request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(store: &Store, request: Request) {
+    info!("{} {}", request.method(), request.url());
+    let result = match request.method() {
+        Method::Get | Method::Head => match key_from_url(request.url()) {
+            Some(key) => match find(store, &key) {
+                Some(path) => respond_with_file(request, &path),
+                None => match find_source(store, &key) {
+                    Some(data) => respond_with_data(request, data),
+                    None => request.respond(Response::empty(StatusCode(404))),
+                },
+            },
+            None => request.respond(Response::empty(StatusCode(400))),
+        },
+        _ => request.respond(Response::empty(StatusCode(405))),
+    };
+    if let Err(error) = result {
+        warn!("Failed to send response: {error}");
+    }
+}
+
+fn respond_with_file(request: Request, path: &Path) -> std::io::Result<()> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) => {
+            warn!("Failed to open '{}': {error}", path.display());
+            return request.respond(Response::empty(StatusCode(500)));
+        }
+    };
+    request.respond(Response::from_file(file).with_header(content_type()))
+}
+
+fn respond_with_data(request: Request, data: Vec<u8>) -> std::io::Result<()> {
+    request.respond(Response::from_data(data).with_header(content_type()))
+}
+
+fn content_type() -> Header {
+    Header::from_bytes("Content-Type", "application/octet-stream").unwrap()
+}
+
+/// Converts the URL of a request into a key. Returns `None` for URLs which
+/// would escape the store or point into its `000Admin` folder.
+fn key_from_url(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let path = percent_encoding::percent_decode_str(path)
+        .decode_utf8()
+        .ok()?;
+    let key = path.strip_prefix('/').unwrap_or(&path);
+    let admin = key
+        .split('/')
+        .next()
+        .is_some_and(|first| first.eq_ignore_ascii_case("000Admin"));
+    if admin || !key.split('/').all(file::is_valid_name) {
+        return None;
+    }
+    Some(key.to_string())
+}
+
+fn find(store: &Store, key: &str) -> Option<PathBuf> {
+    if let Some(path) = &store.path {
+        let path = path.join(symsrv::store_path(key, symsrv::has_index2(path)));
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+
+    let cache = store.cache.as_ref()?;
+    if store.servers.is_empty() {
+        return None;
+    }
+    let result = match KeyParts::from_default_key(key) {
+        Some(parts) => {
+            download::fetch_cached_file(&store.servers, cache, &parts, store.timeout, store.rt)
+        }
+        None => download::fetch_cached(&store.servers, cache, key, store.timeout, store.rt),
+    };
+    match result {
+        Ok(path) => Some(path),
+        Err(error) => {
+            info!("{error}");
+            None
+        }
+    }
+}
+
+/// Extracts the source file of a key like `buildid/<id>/source/<path>` from
+/// the source bundle of the build-id.
+fn find_source(store: &Store, key: &str) -> Option<Vec<u8>> {
+    let (id, path) = key.strip_prefix("buildid/")?.split_once("/source/")?;
+    let bundle = find(store, &format!("buildid/{id}/sourcebundle"))?;
+    match source_from_bundle(&bundle, &format!("/{path}")) {
+        Ok(source) => source,
+        Err(error) => {
+            warn!("{error:#}");
+            None
+        }
+    }
+}
+
+fn source_from_bundle(bundle: &Path, path: &str) -> Result<Option<Vec<u8>>> {
+    let view = ByteView::open(bundle).context(format!("Failed to open '{}'", bundle.display()))?;
+    let bundle = SourceBundle::parse(&view)?;
+    let session = bundle.debug_session()?;
+    let source = session.source_by_path(path)?;
+    Ok(source.and_then(|source| {
+        source
+            .contents()
+            .map(|contents| contents.as_bytes().to_vec())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_from_urls() {
+        assert_eq!(
+            key_from_url("/buildid/abcd/debuginfo?x=1").as_deref(),
+            Some("buildid/abcd/debuginfo")
+        );
+        assert_eq!(
+            key_from_url("/buildid/abcd/source/usr/src/a%20b.c").as_deref(),
+            Some("buildid/abcd/source/usr/src/a b.c")
+        );
+        for url in [
+            "",
+            "/",
+            "/buildid/../../etc/passwd",
+            "/buildid/%2e%2e/%2e%2e/etc/passwd",
+            "/buildid/./abcd/debuginfo",
+            "//etc/passwd",
+            "/buildid//abcd/debuginfo",
+            "/buildid/abcd/",
+            "/buildid\\..\\..\\etc\\passwd",
+            "/buildid%5c..%5cetc",
+            "/buildid/%00/debuginfo",
+            "/000Admin/server.txt",
+            "/000admin/history.txt",
+        ] {
+            assert!(key_from_url(url).is_none(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use symbolic_common::ByteView;
+use symbolic_debuginfo::Object;
+use tokio::runtime::Runtime;
+
+use crate::cache::Cache;
+use crate::config;
+use crate::download;
+use crate::output::{self, Event};
+use crate::symstore::file;
+
+/// Downloads the source file `path` of the file with the given build-id.
+pub fn source(
+    servers: &[config::RemoteStorage],
+    cache: &Cache,
+    id: &str,
+    path: &str,
+    output: Option<&Path>,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<()> {
+    let key = source_key(id, path)?;
+    let cached = download::fetch_cached(servers, cache, &key, timeout, rt)?;
+    let dest = match output {
+        Some(output) => {
+            std::fs::copy(&cached, output).context(format!(
+                "Failed to copy '{}' to '{}'",
+                cached.display(),
+                output.display()
+            ))?;
+            output
+        }
+        None => &cached,
+    };
+    output::emit(
+        &Event::Source {
+            source: path,
+            path: Some(dest),
+            error: None,
+        },
+        format_args!("Downloaded '{}' to '{}'", path, dest.display()),
+    );
+    Ok(())
+}
+
+/// Downloads every source file referenced by the debug info of `object`.
+pub fn sources_for_object(
+    servers: &[config::RemoteStorage],
+    cache: &Cache,
+    object: &Path,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<()> {
+    let view = ByteView::open(object).context(format!("Failed to open '{}'", object.display()))?;
+    let obj = Object::parse(&view).context(format!("Failed to parse '{}'", object.display()))?;
+    let id = obj
+        .code_id()
+        .ok_or_else(|| anyhow!("'{}' has no build-id", object.display()))?;
+    let session = obj.debug_session().context(format!(
+        "Failed to read debug info of '{}'",
+        object.display()
+    ))?;
+
+    let paths = session
+        .files()
+        .filter_map(|file| file.ok())
+        .map(|file| file.abs_path_str())
+        .filter(|path| path.starts_with('/'))
+        .collect::<BTreeSet<_>>();
+    if paths.is_empty() {
+        return Err(anyhow!(
+            "'{}' does not reference any source files",
+            object.display()
+        ));
+    }
+
+    let mut missing = 0;
+    for path in &paths {
+        let result = source_key(id.as_str(), path)
+            .and_then(|key| download::fetch_cached(servers, cache, &key, timeout, rt));
+        match result {
+            Ok(cached) => output::emit(
+                &Event::Source {
+                    source: path,
+                    path: Some(&cached),
+                    error: None,
+                },
+                format_args!("Downloaded '{}' to '{}'", path, cached.display()),
+            ),
+            Err(error) => {
+                warn!("Failed to download '{}': {}", path, error);
+                output::json(&Event::Source {
+                    source: path,
+                    path: None,
+                    error: Some(format!("{error:#}")),
+                });
+                missing += 1;
+            }
+        }
+    }
+
+    if missing > 0 {
+        Err(anyhow!(
+            "Unable to download {} of {} source files",
+            missing,
+            paths.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Computes the key of a source file, rejecting paths which are not absolute
+/// or which would escape the source folder of the build-id.
+fn source_key(id: &str, path: &str) -> Result<String> {
+    let cleaned = symbolic_common::clean_path(path);
+    let segments = match cleaned.strip_prefix('/') {
+        Some(relative) => relative
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+    if segments.is_empty() || !segments.iter().all(|segment| file::is_valid_name(segment)) {
+        return Err(anyhow!("Invalid source path '{}'", path));
+    }
+    let id = symbolic_common::CodeId::new(id.to_string());
+    if id.is_nil() {
+        return Err(anyhow!("Invalid build-id"));
+    }
+    Ok(file::source_key(id.as_str(), &segments.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "17e4599cd1d5f4fece3caf939ef4962bed010385";
+
+    #[test]
+    fn source_keys() {
+        assert_eq!(
+            source_key(ID, "/usr/src/a.c").unwrap(),
+            format!("buildid/{ID}/source/usr/src/a.c")
+        );
+        assert_eq!(
+            source_key(ID, "/usr//src/./b/../a.c").unwrap(),
+            format!("buildid/{ID}/source/usr/src/a.c")
+        );
+        for path in [
+            "",
+            "/",
+            "//",
+            "a.c",
+            "src/a.c",
+            "../a.c",
+            "/../a.c",
+            "/usr/src\\..\\..\\a.c",
+            "/usr/src\\..\\..\\..\\a.c",
+            "C:\\src\\a.c",
+            "\\\\server\\share\\a.c",
+            "/usr/src/a\0.c",
+        ] {
+            assert!(source_key(ID, path).is_err(), "{:?}", path);
+        }
+        assert!(source_key("", "/usr/src/a.c").is_err());
+        assert!(source_key("../..", "/usr/src/a.c").is_err());
+        assert_eq!(
+            source_key("../AB/cd", "/a.c").unwrap(),
+            "buildid/abcd/source/a.c"
+        );
+    }
+}
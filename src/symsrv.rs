@@ -0,0 +1,157 @@
+// Helpers for the Microsoft SymSrv protocol. Files are stored as
+// `{filename}/{identifier}/{filename}`, optionally compressed into a CAB file
+// whose name has the last character replaced with an underscore (e.g.
+// `foo.pd_`), or redirected with a `file.ptr` file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config::PathConfig;
+use crate::symstore::file;
+use crate::transaction;
+
+/// Marker of a two-tier store, in which each `{filename}` folder is in a
+/// folder named after the first two characters of the filename, e.g.
+/// `fo/foo.pdb/<id>/foo.pdb`.
+const INDEX2: &str = "index2.txt";
+
+/// The contents of a `file.ptr` redirect.
+pub enum FilePtr {
+    /// The file lives at the given path or URL.
+    Path(String),
+    /// The file is not available. The message explains why.
+    Msg(String),
+}
+
+/// Splits a SymSrv key into its `{filename}/{identifier}` folder and the
+/// filename. Returns `None` if the key is not in the SymSrv layout.
+pub fn split_key(key: &str) -> Option<(&str, &str)> {
+    let (dir, filename) = key.rsplit_once('/')?;
+    let (name, identifier) = dir.split_once('/')?;
+    let valid = [name, identifier, filename]
+        .iter()
+        .all(|part| file::is_valid_name(part));
+    if valid && name.eq_ignore_ascii_case(filename) {
+        Some((dir, filename))
+    } else {
+        None
+    }
+}
+
+/// Whether the store `config` is a two-tier store.
+pub fn is_two_tier(config: &PathConfig) -> bool {
+    config.two_tier || has_index2(&config.path)
+}
+
+/// Whether the store at `root` has an `index2.txt`, which makes it a two-tier
+/// store.
+pub fn has_index2(root: &Path) -> bool {
+    root.join(INDEX2).is_file()
+}
+
+/// Returns the path of `key` relative to the root of a store.
+pub fn store_path(key: &str, two_tier: bool) -> PathBuf {
+    if !two_tier {
+        return PathBuf::from(key);
+    }
+    let name = key.split('/').next().unwrap_or(key);
+    let tier = name.chars().take(2).collect::<String>();
+    Path::new(&tier).join(key)
+}
+
+/// Marks the store at `root` as a two-tier store. Only a store without files
+/// can be marked, since the files of a one-tier store would not be found
+/// anymore.
+pub fn init_two_tier(root: &Path) -> Result<()> {
+    let index2 = root.join(INDEX2);
+    if index2.is_file() {
+        return Ok(());
+    }
+    let entries =
+        std::fs::read_dir(root).context(format!("Failed to read '{}'", root.display()))?;
+    for entry in entries {
+        let name = entry?.file_name();
+        if name != transaction::ADMIN_DIR && name != transaction::PING_ME {
+            return Err(anyhow!(
+                "'{}' already holds files of a one-tier store, convert it with convertstore.exe first",
+                root.display()
+            ));
+        }
+    }
+    std::fs::File::create(&index2).context(format!("Failed to create '{}'", index2.display()))?;
+    Ok(())
+}
+
+/// Returns the name of the compressed version of `filename`.
+pub fn compressed_name(filename: &str) -> String {
+    let mut name = filename.to_string();
+    name.pop();
+    name.push('_');
+    name
+}
+
+pub fn parse_file_ptr(contents: &str) -> Result<FilePtr> {
+    let contents = contents.trim();
+    if let Some(path) = contents.strip_prefix("PATH:") {
+        Ok(FilePtr::Path(path.to_string()))
+    } else if let Some(msg) = contents.strip_prefix("MSG:") {
+        Ok(FilePtr::Msg(msg.to_string()))
+    } else {
+        Err(anyhow!("Malformed file.ptr '{}'", contents))
+    }
+}
+
+/// Extracts the single file in the CAB archive `cab` to `dest`.
+pub fn decompress(cab: &Path, dest: &Path) -> Result<()> {
+    let file = std::fs::File::open(cab).context(format!("Failed to open '{}'", cab.display()))?;
+    let mut cabinet =
+        cab::Cabinet::new(file).context(format!("'{}' is not a valid CAB file", cab.display()))?;
+    let name = cabinet
+        .folder_entries()
+        .flat_map(|folder| folder.file_entries())
+        .map(|entry| entry.name().to_string())
+        .next()
+        .ok_or_else(|| anyhow!("'{}' is empty", cab.display()))?;
+
+    let mut reader = cabinet.read_file(&name)?;
+    let mut out =
+        std::fs::File::create(dest).context(format!("Failed to create '{}'", dest.display()))?;
+    std::io::copy(&mut reader, &mut out)
+        .context(format!("Failed to decompress '{}'", cab.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_keys() {
+        assert_eq!(
+            split_key("foo.pdb/ABCD1/foo.pdb"),
+            Some(("foo.pdb/ABCD1", "foo.pdb"))
+        );
+        assert_eq!(
+            split_key("Foo.pdb/ABCD1/foo.PDB"),
+            Some(("Foo.pdb/ABCD1", "foo.PDB"))
+        );
+        for key in [
+            "",
+            "foo.pdb",
+            "foo.pdb/foo.pdb",
+            "foo.pdb/ABCD1/bar.pdb",
+            "foo.pdb/a/b/foo.pdb",
+            "foo.pdb//foo.pdb",
+            "/foo.pdb/ABCD1/foo.pdb",
+            "foo.pdb/ABCD1/foo.pdb/",
+            "foo.pdb/../foo.pdb",
+            "foo.pdb/./foo.pdb",
+            "../ABCD1/..",
+            "foo.pdb/..\\..\\x/foo.pdb",
+            "buildid/ABCD1/debuginfo",
+        ] {
+            assert!(split_key(key).is_none(), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Static libraries (`.a`, `.lib`), which are `ar` archives of object files.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+use symbolic_common::ByteView;
+
+use crate::symstore::file::{self, FileInfo, Member};
+
+const MAGIC: &[u8] = b"!<arch>\n";
+
+/// The members of a static library.
+pub struct Members {
+    /// The objects of the members which have an identifier.
+    pub objects: Vec<FileInfo>,
+    /// Names of the members which are not objects with an identifier.
+    pub unidentified: Vec<String>,
+}
+
+/// Identifies the objects in the members of the static library at `path`.
+/// Returns `None` if the file is not a static library.
+pub fn members_to_info(path: &Path) -> Result<Option<Members>> {
+    let view = ByteView::open(path).context(format!("Failed to open '{}'", path.display()))?;
+    if !view.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    trace!("Inspecting members of {}", path.display());
+    let archive = goblin::archive::Archive::parse(&view)
+        .context(format!("Failed to parse '{}'", path.display()))?;
+
+    let mut members = Members {
+        objects: Vec::new(),
+        unidentified: Vec::new(),
+    };
+    for index in 0..archive.len() {
+        let Some(entry) = archive.get_at(index) else {
+            continue;
+        };
+        let member = Member {
+            name: entry.extended_name().to_string(),
+            offset: entry.offset as usize,
+            size: entry.size(),
+        };
+        let data = member
+            .offset
+            .checked_add(member.size)
+            .and_then(|end| view.get(member.offset..end));
+        let objects = match data {
+            Some(data) => file::data_to_info(path, data),
+            None => Vec::new(),
+        };
+        // The name of a member becomes part of its key, so members whose
+        // name could leave the store are not uploaded either
+        let name = file::base_name(&member.name);
+        if objects.is_empty() || !file::is_valid_name(&name) {
+            members.unidentified.push(member.name);
+            continue;
+        }
+        members
+            .objects
+            .extend(objects.into_iter().map(|object| FileInfo {
+                name: name.clone(),
+                member: Some(member.clone()),
+                ..object
+            }));
+    }
+    Ok(Some(members))
+}
+
+/// Writes the member of the static library `file` to its own file in `dir`,
+/// so that the member can be uploaded on its own. The member keeps the name
+/// and key of `file`.
+pub fn extract(file: &FileInfo, dir: &Path) -> Result<FileInfo> {
+    let member = file
+        .member
+        .as_ref()
+        .ok_or_else(|| anyhow!("'{}' is not a static library", file.path.display()))?;
+    trace!(
+        "Extracting member {} of {}",
+        member.name,
+        file.path.display()
+    );
+    let view = file.open()?;
+    let data = view
+        .get(member.offset..member.offset + member.size)
+        .ok_or_else(|| anyhow!("'{}' has no member {}", file.path.display(), member.name))?;
+
+    let (mut out, path) = file::keep_temp_file(dir, "")?;
+    out.write_all(data)
+        .context(format!("Failed to write '{}'", path.display()))?;
+
+    Ok(FileInfo {
+        slice: file.slice,
+        ..file.derived(path, file.resource_type, file.name.clone())?
+    })
+}
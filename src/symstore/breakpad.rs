@@ -0,0 +1,244 @@
+// Conversion of objects into Breakpad text symbol files. The format is
+// documented at
+// https://chromium.googlesource.com/breakpad/breakpad/+/master/docs/symbol_files.md
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::{trace, warn};
+use symbolic_cfi::AsciiCfiWriter;
+use symbolic_common::join_path;
+use symbolic_debuginfo::{FileFormat, Function, LineInfo, Object};
+use symbolic_demangle::{Demangle, DemangleOptions};
+
+use crate::symstore::file::{self, FileInfo, FileType, ResourceType};
+
+struct FuncRecord {
+    address: u64,
+    size: u64,
+    name: String,
+    lines: Vec<LineRecord>,
+}
+
+struct LineRecord {
+    address: u64,
+    size: u64,
+    line: u64,
+    file: usize,
+}
+
+/// A line record covering `start..end`.
+#[derive(Clone, Copy)]
+struct LineRange<'a, 'd> {
+    start: u64,
+    end: u64,
+    line: &'a LineInfo<'d>,
+}
+
+/// Creates a Breakpad symbol file in `dir` from `file`. Returns `None` if the
+/// object has no debug id.
+///
+/// The symbol file keeps the name of `file` so that it is keyed under the
+/// Breakpad layout of `<name>/<DEBUGID>/<name>.sym`.
+pub fn create(file: &FileInfo, dir: &Path) -> Result<Option<FileInfo>> {
+    trace!("Creating Breakpad symbols for {}", file.path.display());
+    let view = file.open()?;
+    let obj = file::parse_object(&view, file)?;
+    if obj.debug_id().is_nil() {
+        return Ok(None);
+    }
+
+    let (out, path) = file::keep_temp_file(dir, ".sym")?;
+    let mut out = BufWriter::new(out);
+    write_symbols(&obj, &file.name, &mut out).context(format!(
+        "Failed to write Breakpad symbols for '{}'",
+        file.path.display()
+    ))?;
+    out.flush()?;
+    drop(out);
+
+    Ok(Some(FileInfo {
+        file_type: FileType::Breakpad,
+        identifier: obj.debug_id().breakpad().to_string(),
+        ..file.derived(path, ResourceType::DebugInfo, file.name.clone())?
+    }))
+}
+
+fn write_symbols<W: Write>(obj: &Object, name: &str, out: &mut W) -> Result<()> {
+    let os = match obj.file_format() {
+        FileFormat::Elf => "Linux",
+        FileFormat::MachO => "mac",
+        FileFormat::Pe | FileFormat::Pdb => "windows",
+        _ => "unknown",
+    };
+    writeln!(
+        out,
+        "MODULE {} {} {} {}",
+        os,
+        obj.arch().name(),
+        obj.debug_id().breakpad(),
+        name
+    )?;
+    if let Some(code_id) = obj.code_id() {
+        writeln!(out, "INFO CODE_ID {}", code_id.as_str().to_uppercase())?;
+    }
+
+    let mut files = Vec::new();
+    let mut file_ids = HashMap::new();
+    let mut funcs = Vec::new();
+    match obj.debug_session() {
+        Ok(session) => {
+            for function in session.functions() {
+                let function = match function {
+                    Ok(function) => function,
+                    Err(error) => {
+                        warn!("Skipping function: {error}");
+                        continue;
+                    }
+                };
+                if function.size == 0 {
+                    continue;
+                }
+
+                let comp_dir = String::from_utf8_lossy(function.compilation_dir);
+                let lines = line_ranges(&function)
+                    .into_iter()
+                    .map(|range| {
+                        let path = join_path(&comp_dir, &range.line.file.path_str());
+                        let file = *file_ids.entry(path.clone()).or_insert_with(|| {
+                            files.push(path);
+                            files.len() - 1
+                        });
+                        LineRecord {
+                            address: range.start,
+                            size: range.end - range.start,
+                            line: range.line.line,
+                            file,
+                        }
+                    })
+                    .collect();
+
+                funcs.push(FuncRecord {
+                    address: function.address,
+                    size: function.size,
+                    name: function
+                        .name
+                        .try_demangle(DemangleOptions::complete())
+                        .into_owned(),
+                    lines,
+                });
+            }
+        }
+        Err(error) => warn!("Unable to read debug info: {error}"),
+    }
+    funcs.sort_by_key(|func| func.address);
+
+    for (id, path) in files.iter().enumerate() {
+        writeln!(out, "FILE {id} {path}")?;
+    }
+
+    for func in &funcs {
+        writeln!(
+            out,
+            "FUNC {:x} {:x} 0 {}",
+            func.address, func.size, func.name
+        )?;
+        for line in &func.lines {
+            writeln!(
+                out,
+                "{:x} {:x} {} {}",
+                line.address, line.size, line.line, line.file
+            )?;
+        }
+    }
+
+    // Only symbols which are not already described by a FUNC record
+    let func_addresses = funcs.iter().map(|f| f.address).collect::<BTreeSet<_>>();
+    let mut publics = obj
+        .symbols()
+        .filter(|symbol| !func_addresses.contains(&symbol.address))
+        .filter_map(|symbol| Some((symbol.address, symbol.name()?.to_string())))
+        .collect::<Vec<_>>();
+    publics.sort();
+    publics.dedup_by_key(|(address, _)| *address);
+    for (address, name) in publics {
+        let name = symbolic_common::Name::from(name.as_str())
+            .try_demangle(DemangleOptions::complete())
+            .into_owned();
+        writeln!(out, "PUBLIC {address:x} 0 {name}")?;
+    }
+
+    let mut cfi = AsciiCfiWriter::new(out);
+    if let Err(error) = cfi.process(obj) {
+        warn!("Unable to write stack records: {error}");
+    }
+
+    Ok(())
+}
+
+/// Computes the line ranges of `function`. Where a function was inlined, the
+/// lines of the inlined function take precedence over the call site so that
+/// each address maps to the code which was actually executed.
+fn line_ranges<'a, 'd>(function: &'a Function<'d>) -> Vec<LineRange<'a, 'd>> {
+    let mut lines = function.lines.iter().collect::<Vec<_>>();
+    lines.sort_by_key(|line| line.address);
+
+    let mut ranges = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let end = match line.size {
+            Some(size) => line.address + size,
+            None => lines
+                .get(i + 1)
+                .map(|next| next.address)
+                .unwrap_or_else(|| function.end_address()),
+        };
+        if end > line.address {
+            ranges.push(LineRange {
+                start: line.address,
+                end,
+                line,
+            });
+        }
+    }
+
+    for inlinee in &function.inlinees {
+        ranges = overlay(ranges, line_ranges(inlinee));
+    }
+    ranges
+}
+
+/// Replaces the parts of `base` which are covered by `top` with `top`.
+fn overlay<'a, 'd>(
+    base: Vec<LineRange<'a, 'd>>,
+    top: Vec<LineRange<'a, 'd>>,
+) -> Vec<LineRange<'a, 'd>> {
+    let mut result = Vec::with_capacity(base.len() + top.len());
+    for range in base {
+        let mut start = range.start;
+        for covered in top
+            .iter()
+            .filter(|t| t.start < range.end && t.end > range.start)
+        {
+            if covered.start > start {
+                result.push(LineRange {
+                    start,
+                    end: covered.start,
+                    line: range.line,
+                });
+            }
+            start = start.max(covered.end);
+        }
+        if start < range.end {
+            result.push(LineRange {
+                start,
+                end: range.end,
+                line: range.line,
+            });
+        }
+    }
+    result.extend(top);
+    result.sort_by_key(|range| range.start);
+    result
+}
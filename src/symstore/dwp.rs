@@ -0,0 +1,114 @@
+// Split DWARF (`-gsplit-dwarf`), where the compiler leaves most of the debug
+// info in a `.dwo` file per compilation unit, referenced from a skeleton unit
+// in the ELF file. The `.dwo` files are combined into a DWARF package
+// (`.dwp`) which debuggers look for next to the ELF file. See
+// https://gcc.gnu.org/wiki/DebugFissionDWP
+
+use std::cell::Cell;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use goblin::elf::Elf;
+use log::trace;
+use symbolic_common::ByteView;
+use thorin::MissingReferencedObjectBehaviour;
+use typed_arena::Arena;
+
+use crate::symstore::elf;
+use crate::symstore::file::{self, FileInfo, ResourceType};
+
+/// Finds the DWARF package of the ELF file `file`, which is named after the
+/// file with a `.dwp` extension added.
+pub fn find(file: &FileInfo) -> Result<Option<FileInfo>> {
+    let mut path = file.path.clone().into_os_string();
+    path.push(".dwp");
+    let path = PathBuf::from(path);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let view = ByteView::open(&path).context(format!("Failed to open '{}'", path.display()))?;
+    let package = Elf::parse(&view).context(format!("Failed to parse '{}'", path.display()))?;
+    if elf::find_section(&package, ".debug_cu_index").is_none() {
+        return Ok(None);
+    }
+    let name = file::base_name(&path.to_string_lossy());
+    Ok(Some(FileInfo {
+        origin: path.clone(),
+        ..file.derived(path, ResourceType::Dwp, name)?
+    }))
+}
+
+/// Assembles a DWARF package in `dir` from the `.dwo` files referenced by the
+/// ELF file `file`. Returns `None` if the file has no split DWARF.
+pub fn assemble(file: &FileInfo, dir: &Path) -> Result<Option<FileInfo>> {
+    trace!("Assembling DWARF package for {}", file.path.display());
+    let session = Session::default();
+    let mut package = thorin::DwarfPackage::new(&session);
+    if let Err(error) = package.add_executable(&file.path, MissingReferencedObjectBehaviour::Error)
+    {
+        let error = anyhow::Error::from(error);
+        return Err(match session.unreadable.take() {
+            Some(path) => error.context(format!("Failed to read '{}'", path.display())),
+            None => error,
+        });
+    }
+    let object = match package.finish() {
+        Ok(object) => object,
+        Err(thorin::Error::NoOutputObjectCreated) => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    let (out, path) = file::keep_temp_file(dir, ".dwp")?;
+    let mut out = BufWriter::new(out);
+    object
+        .write_stream(&mut out)
+        .map_err(|error| anyhow!("{error}"))
+        .and_then(|()| Ok(out.flush()?))
+        .context(format!("Failed to write '{}'", path.display()))?;
+
+    let name = format!("{}.dwp", file.name);
+    Ok(Some(file.derived(path, ResourceType::Dwp, name)?))
+}
+
+/// Owns the data which the packaging borrows from.
+struct Session<Relocations> {
+    data: Arena<Vec<u8>>,
+    inputs: Arena<ByteView<'static>>,
+    relocations: Arena<Relocations>,
+    /// The last input which could not be read, since the error of the
+    /// packaging does not say which.
+    unreadable: Cell<Option<PathBuf>>,
+}
+
+impl<Relocations> Default for Session<Relocations> {
+    fn default() -> Self {
+        Self {
+            data: Arena::new(),
+            inputs: Arena::new(),
+            relocations: Arena::new(),
+            unreadable: Cell::new(None),
+        }
+    }
+}
+
+impl<Relocations> thorin::Session<Relocations> for Session<Relocations> {
+    fn alloc_data(&self, data: Vec<u8>) -> &[u8] {
+        self.data.alloc(data)
+    }
+
+    fn alloc_relocation(&self, data: Relocations) -> &Relocations {
+        self.relocations.alloc(data)
+    }
+
+    fn read_input(&self, path: &Path) -> std::io::Result<&[u8]> {
+        match ByteView::open(path) {
+            Ok(view) => Ok(self.inputs.alloc(view)),
+            Err(error) => {
+                self.unreadable.set(Some(path.to_path_buf()));
+                Err(error)
+            }
+        }
+    }
+}
@@ -0,0 +1,538 @@
+// Compressed debug sections, MiniDebugInfo and links to separate debug files
+// in ELF files.
+//
+// Debug sections are either compressed with a compression header and the
+// SHF_COMPRESSED flag (`-gz`), or named `.zdebug_*` and prefixed with a GNU
+// `ZLIB` header. MiniDebugInfo is the symbol table of a stripped file, stored
+// as an xz-compressed ELF file in its `.gnu_debugdata` section. See
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/MiniDebugInfo.html
+// and https://sourceware.org/gdb/current/onlinedocs/gdb.html/Separate-Debug-Files.html
+
+use std::convert::TryInto;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use goblin::container::Ctx;
+use goblin::elf::compression_header::{CompressionHeader, ELFCOMPRESS_ZLIB};
+use goblin::elf::section_header::{SectionHeader, SHF_COMPRESSED, SHN_XINDEX, SHT_NOBITS};
+use goblin::elf::Elf;
+use log::{info, trace, warn};
+use scroll::Pwrite;
+use symbolic_common::ByteView;
+
+use crate::symstore::dwp;
+use crate::symstore::file::{self, FileInfo, ResourceType};
+
+const MINI_DEBUG_INFO: &str = ".gnu_debugdata";
+const DEBUG_LINK: &str = ".gnu_debuglink";
+const DEBUG_ALT_LINK: &str = ".gnu_debugaltlink";
+/// Largest alignment of a decompressed section, a page. The alignment comes
+/// from the file, so larger ones are rejected rather than padded to.
+const MAX_ALIGN: u64 = 4096;
+/// Where separate debug files are installed.
+const DEBUG_DIR: &str = "/usr/lib/debug";
+/// Not yet defined by goblin.
+const ELFCOMPRESS_ZSTD: u32 = 2;
+
+/// Writes a copy of the ELF file `file` with its debug sections decompressed
+/// to `dir`, for consumers which cannot read compressed debug info. Returns
+/// `None` if none of its sections are compressed.
+///
+/// The decompressed sections and the section headers are appended to the
+/// copy, leaving the rest of the file untouched. Debug sections are not
+/// loaded, so moving them does not change the program.
+pub fn decompress(file: &FileInfo, dir: &Path) -> Result<Option<FileInfo>> {
+    let view = file.open()?;
+    let data = file::parse_object(&view, file)?.data();
+    let elf = Elf::parse(data).context(format!("Failed to parse '{}'", file.path.display()))?;
+    let compressed = (0..elf.section_headers.len())
+        .filter(|&index| is_compressed(&elf, &elf.section_headers[index]))
+        .collect::<Vec<_>>();
+    if compressed.is_empty() {
+        return Ok(None);
+    }
+    info!("{} has compressed debug sections", file.path.display());
+    let ctx = Ctx::new(elf.header.container()?, elf.header.endianness()?);
+
+    let (out, path) = file::keep_temp_file(dir, "")?;
+    let mut out = BufWriter::new(out);
+    out.write_all(data)?;
+    let mut end = data.len() as u64;
+
+    let mut sections = elf.section_headers.clone();
+    let strndx = match u32::from(elf.header.e_shstrndx) {
+        SHN_XINDEX => sections
+            .first()
+            .map_or(usize::MAX, |first| first.sh_link as usize),
+        index => index as usize,
+    };
+    let Some(strings) = sections.get(strndx) else {
+        bail!(
+            "Section name table {} of '{}' is out of bounds",
+            strndx,
+            file.path.display()
+        );
+    };
+    let mut names = section_data(data, strings)?.to_vec();
+    let mut renamed = false;
+    for index in compressed {
+        let section = &mut sections[index];
+        let name = section_name(&elf, section);
+        let (contents, align) = decompress_section(section_data(data, section)?, name, ctx)
+            .context(format!(
+                "Failed to decompress section {} of '{}'",
+                name,
+                file.path.display()
+            ))?;
+        end = pad(&mut out, end, align)?;
+        out.write_all(&contents)?;
+        section.sh_offset = end;
+        section.sh_size = contents.len() as u64;
+        section.sh_addralign = align;
+        section.sh_flags &= !u64::from(SHF_COMPRESSED);
+        end += contents.len() as u64;
+
+        // The old GNU style also names the section differently
+        if let Some(suffix) = name.strip_prefix(".zdebug") {
+            section.sh_name = names.len();
+            names.extend_from_slice(format!(".debug{suffix}\0").as_bytes());
+            renamed = true;
+        }
+    }
+    if renamed {
+        out.write_all(&names)?;
+        sections[strndx].sh_offset = end;
+        sections[strndx].sh_size = names.len() as u64;
+        end += names.len() as u64;
+    }
+
+    end = pad(&mut out, end, 8)?;
+    let mut buffer = vec![0; SectionHeader::size(ctx)];
+    for section in sections {
+        buffer.pwrite_with(section, 0, ctx)?;
+        out.write_all(&buffer)?;
+    }
+    let mut header = elf.header;
+    header.e_shoff = end;
+    let mut buffer = vec![0; goblin::elf::Header::size(ctx)];
+    buffer.pwrite_with(header, 0, ctx.le)?;
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&buffer)?;
+    out.flush()
+        .context(format!("Failed to write '{}'", path.display()))?;
+    drop(out);
+
+    Ok(Some(file.derived(
+        path,
+        file.resource_type,
+        file.name.clone(),
+    )?))
+}
+
+/// Extracts the MiniDebugInfo of the ELF file `file` to `dir`. Returns `None`
+/// if the file has none.
+pub fn mini_debug_info(file: &FileInfo, dir: &Path) -> Result<Option<FileInfo>> {
+    let view = file.open()?;
+    let data = file::parse_object(&view, file)?.data();
+    let elf = Elf::parse(data).context(format!("Failed to parse '{}'", file.path.display()))?;
+    let Some(section) = find_section(&elf, MINI_DEBUG_INFO) else {
+        return Ok(None);
+    };
+    trace!("Extracting MiniDebugInfo of {}", file.path.display());
+
+    let (out, path) = file::keep_temp_file(dir, "")?;
+    let mut out = BufWriter::new(out);
+    lzma_rs::xz_decompress(&mut section_data(data, section)?, &mut out).context(format!(
+        "Failed to decompress MiniDebugInfo of '{}'",
+        file.path.display()
+    ))?;
+    out.flush()
+        .context(format!("Failed to write '{}'", path.display()))?;
+    drop(out);
+
+    Ok(Some(file.derived(
+        path,
+        ResourceType::MiniDebugInfo,
+        file.name.clone(),
+    )?))
+}
+
+/// Finds the files which hold parts of the debug info of the ELF file `file`:
+/// its separate debug file named by `.gnu_debuglink`, the supplementary file
+/// which `dwz` moved shared debug info into, named by `.gnu_debugaltlink`, and
+/// its DWARF package. Files are looked for where GDB looks for them. Logs a
+/// warning for each linked file which cannot be found.
+///
+/// The supplementary file is keyed by the build-id in the link, which is the
+/// build-id debuggers look it up by, and is only accepted if it has that
+/// build-id.
+pub fn linked_files(file: &FileInfo) -> Result<Vec<FileInfo>> {
+    let view = file.open()?;
+    let data = file::parse_object(&view, file)?.data();
+    let elf = Elf::parse(data).context(format!("Failed to parse '{}'", file.path.display()))?;
+    let dir = file.path.parent().unwrap_or_else(|| Path::new(""));
+    let mut linked = Vec::new();
+
+    if let Some(section) = find_section(&elf, DEBUG_LINK) {
+        let (name, crc) = parse_debug_link(section_data(data, section)?, elf.little_endian)
+            .context(format!(
+                "Malformed {} in '{}'",
+                DEBUG_LINK,
+                file.path.display()
+            ))?;
+        let mut candidates = vec![dir.join(name), dir.join(".debug").join(name)];
+        if let Ok(dir) = dir.canonicalize() {
+            if let Ok(dir) = dir.strip_prefix("/") {
+                candidates.push(Path::new(DEBUG_DIR).join(dir).join(name));
+            }
+        }
+        candidates.extend(build_id_path(&file.identifier));
+        let found = candidates.into_iter().find(|path| {
+            if !path.is_file() || is_same_file(path, &file.path) {
+                return false;
+            }
+            let matches = crc32(path).ok() == Some(crc);
+            if !matches {
+                warn!(
+                    "Ignoring '{}' for '{}' as its CRC does not match",
+                    path.display(),
+                    file.path.display()
+                );
+            }
+            matches
+        });
+        match found {
+            Some(path) => {
+                let infos = file::file_to_info(&path)?;
+                if infos.is_empty() {
+                    warn!("Debug file '{}' is not an object file", path.display());
+                }
+                linked.extend(infos);
+            }
+            None => warn!(
+                "Debug file '{}' of '{}' not found",
+                name,
+                file.path.display()
+            ),
+        }
+    }
+
+    if let Some(section) = find_section(&elf, DEBUG_ALT_LINK) {
+        let (name, build_id) = parse_alt_link(section_data(data, section)?).context(format!(
+            "Malformed {} in '{}'",
+            DEBUG_ALT_LINK,
+            file.path.display()
+        ))?;
+        let identifier = hex::encode(build_id);
+        let mut candidates = vec![normalize(&dir.join(name))];
+        candidates.extend(build_id_path(&identifier));
+        let found = candidates.into_iter().find(|path| {
+            if !path.is_file() {
+                return false;
+            }
+            let matches = has_build_id(path, &identifier);
+            if !matches {
+                warn!(
+                    "Ignoring '{}' for '{}' as its build-id does not match",
+                    path.display(),
+                    file.path.display()
+                );
+            }
+            matches
+        });
+        match found {
+            Some(path) => {
+                let name = file::base_name(&path.to_string_lossy());
+                linked.push(FileInfo {
+                    identifier,
+                    origin: path.clone(),
+                    ..file.derived(path, ResourceType::DebugInfo, name)?
+                });
+            }
+            None => warn!(
+                "Supplementary debug file '{}' of '{}' not found",
+                name,
+                file.path.display()
+            ),
+        }
+    }
+
+    linked.extend(dwp::find(file)?);
+
+    Ok(linked)
+}
+
+/// Parses a `.gnu_debuglink` section, which holds the name of the debug file
+/// followed by the CRC-32 of its contents, aligned to 4 bytes. The name is a
+/// file name, never a path.
+fn parse_debug_link(data: &[u8], little_endian: bool) -> Result<(&str, u32)> {
+    let len = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| anyhow!("Missing file name"))?;
+    let name = std::str::from_utf8(&data[..len])?;
+    if !file::is_valid_name(name) {
+        bail!("Invalid file name '{}'", name);
+    }
+    let offset = (len + 1).next_multiple_of(4);
+    let crc: [u8; 4] = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Missing CRC"))?
+        .try_into()?;
+    let crc = if little_endian {
+        u32::from_le_bytes(crc)
+    } else {
+        u32::from_be_bytes(crc)
+    };
+    Ok((name, crc))
+}
+
+/// Parses a `.gnu_debugaltlink` section, which holds the path of the
+/// supplementary file followed by its build-id.
+fn parse_alt_link(data: &[u8]) -> Result<(&str, &[u8])> {
+    let len = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| anyhow!("Missing file name"))?;
+    let build_id = &data[len + 1..];
+    if build_id.is_empty() {
+        bail!("Missing build-id");
+    }
+    Ok((std::str::from_utf8(&data[..len])?, build_id))
+}
+
+/// Returns where the debug file with the given build-id is installed.
+fn build_id_path(build_id: &str) -> Option<PathBuf> {
+    if build_id.len() <= 2 {
+        return None;
+    }
+    let (dir, name) = build_id.split_at(2);
+    Some(
+        Path::new(DEBUG_DIR)
+            .join(".build-id")
+            .join(dir)
+            .join(format!("{name}.debug")),
+    )
+}
+
+/// Removes the `..` components of `path` which follow a directory, without
+/// resolving symlinks.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Whether the object file at `path` has the build-id `identifier`.
+fn has_build_id(path: &Path, identifier: &str) -> bool {
+    file::file_to_info(path)
+        .map(|infos| {
+            infos
+                .iter()
+                .any(|info| info.identifier.eq_ignore_ascii_case(identifier))
+        })
+        .unwrap_or(false)
+}
+
+fn crc32(path: &Path) -> Result<u32> {
+    let view = ByteView::open(path)?;
+    Ok(crc32fast::hash(&view))
+}
+
+pub fn find_section<'a>(elf: &'a Elf, name: &str) -> Option<&'a SectionHeader> {
+    elf.section_headers
+        .iter()
+        .find(|section| section.sh_type != SHT_NOBITS && section_name(elf, section) == name)
+}
+
+fn section_name<'a>(elf: &'a Elf, section: &SectionHeader) -> &'a str {
+    elf.shdr_strtab.get_at(section.sh_name).unwrap_or("")
+}
+
+fn section_data<'d>(data: &'d [u8], section: &SectionHeader) -> Result<&'d [u8]> {
+    let start = section.sh_offset as usize;
+    let end = start.checked_add(section.sh_size as usize);
+    end.and_then(|end| data.get(start..end))
+        .ok_or_else(|| anyhow!("Section at {start:#x} is out of bounds"))
+}
+
+fn is_compressed(elf: &Elf, section: &SectionHeader) -> bool {
+    section.sh_type != SHT_NOBITS
+        && (section.sh_flags & u64::from(SHF_COMPRESSED) != 0
+            || section_name(elf, section).starts_with(".zdebug"))
+}
+
+/// Decompresses the contents of the section `name`. Returns the contents
+/// along with their alignment.
+fn decompress_section(data: &[u8], name: &str, ctx: Ctx) -> Result<(Vec<u8>, u64)> {
+    if name.starts_with(".zdebug") {
+        // A `ZLIB` magic followed by the big-endian size of the contents
+        let Some(size) = data
+            .strip_prefix(b"ZLIB")
+            .and_then(|rest| rest.get(..8))
+            .map(|size| u64::from_be_bytes(size.try_into().unwrap()))
+        else {
+            bail!("Missing ZLIB header");
+        };
+        // The sizes are not trusted to preallocate the contents, as a corrupt
+        // file could claim any size
+        let mut contents = Vec::new();
+        flate2::read::ZlibDecoder::new(&data[12..]).read_to_end(&mut contents)?;
+        if contents.len() as u64 != size {
+            bail!("Expected {} bytes but got {}", size, contents.len());
+        }
+        return Ok((contents, 1));
+    }
+
+    let header = CompressionHeader::parse(data, 0, ctx)?;
+    let compressed = &data[CompressionHeader::size(ctx)..];
+    let contents = match header.ch_type {
+        ELFCOMPRESS_ZLIB => {
+            let mut contents = Vec::new();
+            flate2::read::ZlibDecoder::new(compressed).read_to_end(&mut contents)?;
+            contents
+        }
+        ELFCOMPRESS_ZSTD => zstd::stream::decode_all(compressed)?,
+        other => bail!("Unknown compression type {other}"),
+    };
+    if contents.len() as u64 != header.ch_size {
+        bail!(
+            "Expected {} bytes but got {}",
+            header.ch_size,
+            contents.len()
+        );
+    }
+    let align = header.ch_addralign.max(1);
+    if !align.is_power_of_two() || align > MAX_ALIGN {
+        bail!("Invalid alignment {}", header.ch_addralign);
+    }
+    Ok((contents, align))
+}
+
+/// Pads `out`, which is at `offset`, with zeroes up to the next multiple of
+/// `align`. Returns the new offset.
+fn pad(out: &mut impl Write, offset: u64, align: u64) -> Result<u64> {
+    let padded = offset
+        .checked_next_multiple_of(align.max(1))
+        .ok_or_else(|| anyhow!("Cannot align offset {offset:#x} to {align}"))?;
+    let padding = padded
+        .checked_sub(offset)
+        .ok_or_else(|| anyhow!("Cannot align offset {offset:#x} to {align}"))?;
+    std::io::copy(&mut std::io::repeat(0).take(padding), out)?;
+    Ok(padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::container::{Container, Endian};
+
+    /// A section compressed with zlib behind an ELF64 compression header
+    /// claiming alignment `align`.
+    fn compressed_section(contents: &[u8], align: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&ELFCOMPRESS_ZLIB.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+        data.extend_from_slice(&align.to_le_bytes());
+        let mut encoder = flate2::write::ZlibEncoder::new(data, flate2::Compression::default());
+        encoder.write_all(contents).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decompressed_sections() {
+        let ctx = Ctx::new(Container::Big, Endian::Little);
+        let data = compressed_section(b"debug info", 8);
+        let (contents, align) = decompress_section(&data, ".debug_info", ctx).unwrap();
+        assert_eq!(contents, b"debug info");
+        assert_eq!(align, 8);
+        let data = compressed_section(b"debug info", 0);
+        assert_eq!(decompress_section(&data, ".debug_info", ctx).unwrap().1, 1);
+    }
+
+    #[test]
+    fn invalid_alignments() {
+        let ctx = Ctx::new(Container::Big, Endian::Little);
+        for align in [3, MAX_ALIGN * 2, 1 << 40, (1 << 63) + 1, u64::MAX] {
+            let data = compressed_section(b"debug info", align);
+            assert!(
+                decompress_section(&data, ".debug_info", ctx).is_err(),
+                "{}",
+                align
+            );
+        }
+    }
+
+    #[test]
+    fn padding() {
+        let mut out = Vec::new();
+        assert_eq!(pad(&mut out, 13, 8).unwrap(), 16);
+        assert_eq!(out, [0; 3]);
+        assert_eq!(pad(&mut out, 16, 8).unwrap(), 16);
+        assert_eq!(out.len(), 3);
+        assert!(pad(&mut out, u64::MAX - 2, 8).is_err());
+        assert_eq!(out.len(), 3);
+    }
+
+    fn debug_link(name: &str, crc: [u8; 4]) -> Vec<u8> {
+        let mut data = name.as_bytes().to_vec();
+        data.push(0);
+        data.resize(data.len().next_multiple_of(4), 0);
+        data.extend(crc);
+        data
+    }
+
+    #[test]
+    fn debug_links() {
+        let data = debug_link("app.debug", [1, 2, 3, 4]);
+        assert_eq!(
+            parse_debug_link(&data, true).unwrap(),
+            ("app.debug", 0x04030201)
+        );
+        assert_eq!(
+            parse_debug_link(&data, false).unwrap(),
+            ("app.debug", 0x01020304)
+        );
+        let data = debug_link("abc", [1, 2, 3, 4]);
+        assert_eq!(parse_debug_link(&data, true).unwrap().0, "abc");
+
+        assert!(parse_debug_link(b"app.debug", true).is_err());
+        assert!(parse_debug_link(b"app.debug\0\0\0", true).is_err());
+        assert!(parse_debug_link(b"app.debug\0\0\0\x01\x02", true).is_err());
+        for name in [
+            "",
+            ".",
+            "..",
+            "../app.debug",
+            "/etc/shadow",
+            "/usr/lib/debug/app.debug",
+            ".debug/app.debug",
+            "..\\app.debug",
+            "C:\\app.debug",
+        ] {
+            let data = debug_link(name, [1, 2, 3, 4]);
+            assert!(parse_debug_link(&data, true).is_err(), "{}", name);
+        }
+    }
+}
@@ -1,11 +1,12 @@
+use anyhow::Context;
 use log::{/*error,*/ /*debug,*/ info, trace, warn};
 use serde::Serialize;
-use std::convert::TryInto;
-use std::fs::File;
-use std::io::Read;
 use std::path::{Path, PathBuf};
 
-use symbolic_debuginfo::Object;
+use symbolic_common::{Arch, ByteView};
+use symbolic_debuginfo::{Archive, FileFormat, Object, ObjectLike};
+
+use crate::symstore::layout::{KeyParts, Layout};
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Serialize)]
 pub enum FileType {
@@ -13,6 +14,10 @@ pub enum FileType {
     Pdb,
     Elf,
     MachO,
+    Breakpad,
+    Wasm,
+    PortablePdb,
+    SourceBundle,
 }
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Serialize)]
@@ -20,91 +25,201 @@ pub enum FileType {
 pub enum ResourceType {
     Executable,
     DebugInfo,
+    SourceBundle,
+    /// The symbol table of a stripped ELF file, taken from its
+    /// `.gnu_debugdata` section.
+    MiniDebugInfo,
+    /// A DWARF package holding the split DWARF (`-gsplit-dwarf`) of an ELF
+    /// file.
+    Dwp,
 }
 
 #[derive(Eq, Hash, PartialEq)]
 pub struct FileInfo {
     pub path: PathBuf,
+    /// Path of the file which was found, e.g. the ELF file a source bundle
+    /// was created from. This is `path` unless the file was generated or
+    /// extracted into a temporary folder.
+    pub origin: PathBuf,
+    /// Name of the file on the server. This is usually the file name of
+    /// `path`, but differs for files generated from another file.
+    pub name: String,
     pub file_type: FileType,
     pub file_size: usize,
     pub identifier: String,
     pub resource_type: ResourceType,
+    pub arch: Arch,
+    /// Index of the object within a fat Mach-O file, which holds one object
+    /// per architecture. `None` for files holding a single object.
+    pub slice: Option<usize>,
+    /// The member of the static library at `path` which holds the object.
+    /// `None` for files which are not static libraries.
+    pub member: Option<Member>,
+}
+
+/// A member of a static library, which is an `ar` archive of object files.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub struct Member {
+    pub name: String,
+    /// Offset of the contents of the member within the library.
+    pub offset: usize,
+    pub size: usize,
 }
 
 impl FileInfo {
-    pub fn key(&self) -> String {
-        match self.file_type {
-            FileType::Elf => match self.resource_type {
-                ResourceType::Executable => {
-                    format!("buildid/{}/executable", self.identifier)
-                }
-                ResourceType::DebugInfo => {
-                    format!("buildid/{}/debuginfo", self.identifier)
-                }
-            },
-            FileType::MachO => match self.resource_type {
-                ResourceType::Executable => {
-                    format!(
-                        "{filename}/mach-uuid-{note}/{filename}",
-                        filename = self.path.file_name().unwrap().to_str().unwrap(),
-                        note = self.identifier
-                    )
-                }
-                ResourceType::DebugInfo => {
-                    format!(
-                        "_.dwarf/mach-uuid-sym-{note}/_.dwarf",
-                        note = self.identifier
-                    )
-                }
-            },
-            FileType::Pdb | FileType::Pe => {
-                format!(
-                    "{filename}/{identifier}/{filename}",
-                    filename = self.path.file_name().unwrap().to_str().unwrap(),
-                    identifier = self.identifier
-                )
-            }
-        }
+    /// Computes the key of the file on a server with the given layout. Fails
+    /// if the layout cannot key the file, e.g. a build-id which is too short
+    /// for a `.build-id` tree.
+    pub fn key(&self, layout: &Layout) -> anyhow::Result<String> {
+        let parts = KeyParts {
+            file_type: self.file_type,
+            resource_type: self.resource_type,
+            identifier: self.identifier.clone(),
+            filename: Some(self.name.clone()),
+            arch: Some(self.arch),
+        };
+        layout.key(&parts).context("Failed to compute the key")
+    }
+
+    /// Memory maps the file.
+    pub fn open(&self) -> anyhow::Result<ByteView<'static>> {
+        ByteView::open(&self.path).context(format!("Failed to open '{}'", self.path.display()))
+    }
+
+    /// Describes the file at `path`, which was generated from or belongs to
+    /// this file, e.g. its source bundle. The file keeps the type, identifier
+    /// and architecture of this file, and is named `name` on the server.
+    pub fn derived(
+        &self,
+        path: PathBuf,
+        resource_type: ResourceType,
+        name: String,
+    ) -> anyhow::Result<FileInfo> {
+        let file_size = std::fs::metadata(&path)
+            .context(format!("Failed to read '{}'", path.display()))?
+            .len() as usize;
+        Ok(FileInfo {
+            path,
+            origin: self.origin.clone(),
+            name,
+            file_type: self.file_type,
+            file_size,
+            identifier: self.identifier.clone(),
+            resource_type,
+            arch: self.arch,
+            slice: None,
+            member: None,
+        })
     }
 }
 
-pub fn file_to_info(path: &std::path::Path) -> Result<Option<FileInfo>, std::io::Error> {
+/// Creates a file in `dir` for a file generated from another file, which is
+/// kept when it is closed. Returns the file along with its path.
+pub fn keep_temp_file(dir: &Path, suffix: &str) -> anyhow::Result<(std::fs::File, PathBuf)> {
+    tempfile::Builder::new()
+        .suffix(suffix)
+        .tempfile_in(dir)
+        .and_then(|f| f.keep().map_err(|e| e.error))
+        .context(format!("Failed to create file in '{}'", dir.display()))
+}
+
+/// Computes the key of a source file referenced by the debug info of the file
+/// with the given build-id. `path` is the absolute path of the source file at
+/// compile time.
+pub fn source_key(identifier: &str, path: &str) -> String {
+    format!(
+        "buildid/{identifier}/source/{path}",
+        path = path.trim_start_matches('/')
+    )
+}
+
+/// Identifies the objects in the file at `path`. This is a single object for
+/// most files, and one object per architecture for fat Mach-O files. Returns
+/// an empty list if the file is not an object file.
+///
+/// The file is memory mapped rather than read, so that large debug files do
+/// not need to fit into memory.
+pub fn file_to_info(path: &std::path::Path) -> Result<Vec<FileInfo>, std::io::Error> {
     trace!("Inspecting file {}", path.display());
-    let mut file = File::open(path).map_err(|err| {
+    let view = ByteView::open(path).map_err(|err| {
         warn!("Unable to open file {}", path.display());
         warn!("Error: {err}");
         err
     })?;
 
-    let filesize: usize = file
-        .metadata()
-        .map_err(|err| {
-            warn!("Unable to get metadata for file {}", path.display());
-            warn!("Error: {err}");
-            err
-        })?
-        .len()
-        .try_into()
-        .unwrap();
-
-    let mut buffer = Vec::with_capacity(filesize);
-
-    file.read_to_end(&mut buffer).map_err(|err| {
-        warn!("Unable to read to end of file {}", path.display());
-        warn!("Error: {err}");
-        err
-    })?;
+    Ok(data_to_info(path, &view))
+}
 
-    let result = match Object::parse(&buffer) {
-        Ok(obj) => object_to_info(path, filesize, &obj),
+/// Identifies the objects in `data`, which holds the contents of the file at
+/// `path` or of one of its members.
+pub fn data_to_info(path: &Path, data: &[u8]) -> Vec<FileInfo> {
+    if symbolic_debuginfo::peek(data, true) == FileFormat::Unknown {
+        trace!("{} is not an object file", path.display());
+        return Vec::new();
+    }
+
+    let archive = match Archive::parse(data) {
+        Ok(archive) => archive,
         Err(err) => {
             info!("Failed to parse file {}", path.display());
             info!("Error: {err:?}");
-            None
+            return Vec::new();
+        }
+    };
+
+    let mut infos = Vec::new();
+    for (index, obj) in archive.objects().enumerate() {
+        let slice = archive.is_multi().then_some(index);
+        match obj {
+            Ok(obj) => match object_to_info(path, obj.data().len(), &obj) {
+                Some(info) => infos.push(FileInfo { slice, ..info }),
+                None => warn!("{} ({}) has no key", path.display(), obj.arch().name()),
+            },
+            Err(err) => {
+                info!("Failed to parse object {} of {}", index, path.display());
+                info!("Error: {err:?}");
+            }
         }
+    }
+    infos
+}
+
+/// Parses the object of `file` from `view`, which holds the contents of
+/// `file.path`.
+pub fn parse_object<'d>(view: &'d ByteView, file: &FileInfo) -> anyhow::Result<Object<'d>> {
+    parse_object_in(view, file).context(format!("Failed to parse '{}'", file.path.display()))
+}
+
+fn parse_object_in<'d>(view: &'d ByteView, file: &FileInfo) -> anyhow::Result<Object<'d>> {
+    let data = match &file.member {
+        Some(member) => view
+            .get(member.offset..member.offset + member.size)
+            .ok_or_else(|| {
+                anyhow::anyhow!("'{}' has no member {}", file.path.display(), member.name)
+            })?,
+        None => view,
     };
+    let Some(slice) = file.slice else {
+        return Ok(Object::parse(data)?);
+    };
+    Archive::parse(data)?
+        .object_by_index(slice)?
+        .ok_or_else(|| anyhow::anyhow!("'{}' has no object {}", file.path.display(), slice))
+}
 
-    Ok(result)
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+/// Returns the last component of `name`, which may be a Windows or Unix path.
+pub fn base_name(name: &str) -> String {
+    name.rsplit(['/', '\\']).next().unwrap_or(name).to_string()
+}
+
+/// Whether `name` can be a component of a key. Names read from files, e.g.
+/// the module of a Breakpad file, could otherwise leave the store.
+pub fn is_valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0'])
 }
 
 fn object_to_info(path: &Path, filesize: usize, obj: &Object) -> Option<FileInfo> {
@@ -113,7 +228,10 @@ fn object_to_info(path: &Path, filesize: usize, obj: &Object) -> Option<FileInfo
         Object::Pdb(pdb) => Some(pdb_to_info(path, filesize, pdb)),
         Object::Elf(elf) => elf_to_info(path, filesize, elf),
         Object::MachO(macho) => macho_to_info(path, filesize, macho),
-        _ => None,
+        Object::Wasm(wasm) => wasm_to_info(path, filesize, wasm),
+        Object::PortablePdb(ppdb) => Some(ppdb_to_info(path, filesize, ppdb)),
+        Object::Breakpad(breakpad) => breakpad_to_info(path, filesize, breakpad),
+        Object::SourceBundle(bundle) => bundle_to_info(path, filesize, bundle),
     }
 }
 
@@ -124,10 +242,15 @@ fn pe_to_info(
 ) -> Option<FileInfo> {
     pe.code_id().map(|code_id| FileInfo {
         path: path.to_path_buf(),
+        origin: path.to_path_buf(),
+        name: file_name(path),
         file_type: FileType::Pe,
         file_size: filesize,
         identifier: code_id.to_string(),
         resource_type: ResourceType::Executable,
+        arch: pe.arch(),
+        slice: None,
+        member: None,
     })
 }
 
@@ -139,10 +262,15 @@ fn pdb_to_info(path: &Path, filesize: usize, pdb: &symbolic_debuginfo::pdb::PdbO
     );
     FileInfo {
         path: path.to_path_buf(),
+        origin: path.to_path_buf(),
+        name: file_name(path),
         file_type: FileType::Pdb,
         file_size: filesize,
         identifier: id,
         resource_type: ResourceType::DebugInfo,
+        arch: pdb.arch(),
+        slice: None,
+        member: None,
     }
 }
 
@@ -155,18 +283,28 @@ fn elf_to_info(
         if elf.has_debug_info() {
             Some(FileInfo {
                 path: path.to_path_buf(),
+                origin: path.to_path_buf(),
+                name: file_name(path),
                 file_type: FileType::Elf,
                 file_size: filesize,
                 identifier: code_id.to_string(),
                 resource_type: ResourceType::DebugInfo,
+                arch: elf.arch(),
+                slice: None,
+                member: None,
             })
         } else {
             Some(FileInfo {
                 path: path.to_path_buf(),
+                origin: path.to_path_buf(),
+                name: file_name(path),
                 file_type: FileType::Elf,
                 file_size: filesize,
                 identifier: code_id.to_string(),
                 resource_type: ResourceType::Executable,
+                arch: elf.arch(),
+                slice: None,
+                member: None,
             })
         }
     } else {
@@ -183,21 +321,195 @@ fn macho_to_info(
         if macho.has_debug_info() {
             Some(FileInfo {
                 path: path.to_path_buf(),
+                origin: path.to_path_buf(),
+                name: file_name(path),
                 file_type: FileType::MachO,
                 file_size: filesize,
                 identifier: code_id.to_string(),
                 resource_type: ResourceType::DebugInfo,
+                arch: macho.arch(),
+                slice: None,
+                member: None,
             })
         } else {
             Some(FileInfo {
                 path: path.to_path_buf(),
+                origin: path.to_path_buf(),
+                name: file_name(path),
                 file_type: FileType::MachO,
                 file_size: filesize,
                 identifier: code_id.to_string(),
                 resource_type: ResourceType::Executable,
+                arch: macho.arch(),
+                slice: None,
+                member: None,
             })
         }
     } else {
         None
     }
 }
+
+fn wasm_to_info(
+    path: &Path,
+    filesize: usize,
+    wasm: &symbolic_debuginfo::wasm::WasmObject,
+) -> Option<FileInfo> {
+    let code_id = wasm.code_id()?;
+    let resource_type = if wasm.has_debug_info() {
+        ResourceType::DebugInfo
+    } else {
+        ResourceType::Executable
+    };
+    Some(FileInfo {
+        path: path.to_path_buf(),
+        origin: path.to_path_buf(),
+        name: file_name(path),
+        file_type: FileType::Wasm,
+        file_size: filesize,
+        identifier: code_id.to_string(),
+        resource_type,
+        arch: wasm.arch(),
+        slice: None,
+        member: None,
+    })
+}
+
+/// Portable PDBs are keyed like PDBs, except that SymSrv always uses an age
+/// of `FFFFFFFF` for them.
+fn ppdb_to_info(
+    path: &Path,
+    filesize: usize,
+    ppdb: &symbolic_debuginfo::ppdb::PortablePdbObject,
+) -> FileInfo {
+    FileInfo {
+        path: path.to_path_buf(),
+        origin: path.to_path_buf(),
+        name: file_name(path),
+        file_type: FileType::PortablePdb,
+        file_size: filesize,
+        identifier: format!("{:X}FFFFFFFF", ppdb.debug_id().uuid().as_simple()),
+        resource_type: ResourceType::DebugInfo,
+        arch: ppdb.arch(),
+        slice: None,
+        member: None,
+    }
+}
+
+/// Breakpad symbol files are keyed by the name of the module they describe,
+/// which need not match the name of the symbol file.
+fn breakpad_to_info(
+    path: &Path,
+    filesize: usize,
+    breakpad: &symbolic_debuginfo::breakpad::BreakpadObject,
+) -> Option<FileInfo> {
+    if breakpad.debug_id().is_nil() {
+        return None;
+    }
+    let name = base_name(breakpad.name());
+    if !is_valid_name(&name) {
+        warn!(
+            "Invalid module name '{}' in {}",
+            breakpad.name(),
+            path.display()
+        );
+        return None;
+    }
+    Some(FileInfo {
+        path: path.to_path_buf(),
+        origin: path.to_path_buf(),
+        name,
+        file_type: FileType::Breakpad,
+        file_size: filesize,
+        identifier: breakpad.debug_id().breakpad().to_string(),
+        resource_type: ResourceType::DebugInfo,
+        arch: breakpad.arch(),
+        slice: None,
+        member: None,
+    })
+}
+
+/// Source bundles are keyed by the debug id and name of the object they were
+/// created from, which are recorded in the bundle when it is written.
+fn bundle_to_info(
+    path: &Path,
+    filesize: usize,
+    bundle: &symbolic_debuginfo::sourcebundle::SourceBundle,
+) -> Option<FileInfo> {
+    if bundle.debug_id().is_nil() {
+        return None;
+    }
+    let name = match bundle.name().map(base_name) {
+        Some(name) if is_valid_name(&name) => format!("{name}.src.zip"),
+        _ => file_name(path),
+    };
+    let (file_type, identifier) = bundle_object(bundle).unwrap_or_else(|| {
+        (
+            FileType::SourceBundle,
+            bundle.debug_id().breakpad().to_string(),
+        )
+    });
+    Some(FileInfo {
+        path: path.to_path_buf(),
+        origin: path.to_path_buf(),
+        name,
+        file_type,
+        file_size: filesize,
+        identifier,
+        resource_type: ResourceType::SourceBundle,
+        arch: bundle.arch(),
+        slice: None,
+        member: None,
+    })
+}
+
+/// Returns the type and identifier of the object which `bundle` was created
+/// from, so that the bundle is keyed like `upload --with-sources` keys it.
+/// Bundles record the name, code-id and debug-id of the object but not its
+/// type, which is told apart by the extension of the name and by how the
+/// debug-id was derived from the code-id. Returns `None` if the bundle does
+/// not tell.
+fn bundle_object(
+    bundle: &symbolic_debuginfo::sourcebundle::SourceBundle,
+) -> Option<(FileType, String)> {
+    let name = base_name(bundle.name()?).to_ascii_lowercase();
+    let debug_id = bundle.debug_id();
+    if name.ends_with(".pdb") {
+        let id = format!(
+            "{sig:X}{age:X}",
+            sig = debug_id.uuid().as_simple(),
+            age = debug_id.appendix()
+        );
+        return Some((FileType::Pdb, id));
+    }
+
+    let code_id = bundle.code_id()?;
+    let build_id = hex::decode(code_id.as_str()).ok()?;
+    let file_type = if name.ends_with(".wasm") {
+        FileType::Wasm
+    } else if debug_id.appendix() == 0 && is_elf_debug_id(&build_id, debug_id.uuid().as_bytes()) {
+        FileType::Elf
+    } else if debug_id.appendix() == 0 && build_id == debug_id.uuid().as_bytes() {
+        FileType::MachO
+    } else {
+        FileType::Pe
+    };
+    Some((file_type, code_id.to_string()))
+}
+
+/// Whether `uuid` is the debug-id which symbolic derives from the ELF
+/// build-id `build_id`: its first 16 bytes, with the fields of the UUID
+/// swapped for little endian files. A big endian file with a 16 byte build-id
+/// cannot be told apart from a Mach-O file, whose UUID is kept as it is.
+fn is_elf_debug_id(build_id: &[u8], uuid: &[u8; 16]) -> bool {
+    let mut data = [0; 16];
+    let len = build_id.len().min(16);
+    data[..len].copy_from_slice(&build_id[..len]);
+    if build_id.len() != 16 && &data == uuid {
+        return true;
+    }
+    data[0..4].reverse();
+    data[4..6].reverse();
+    data[6..8].reverse();
+    &data == uuid
+}
@@ -0,0 +1,526 @@
+// The layout of a server, which determines the key under which each file is
+// stored. Consumers expect different trees for the same files, e.g. debuginfod
+// clients ask for `buildid/<id>/debuginfo` while LLDB and GDB search
+// `.build-id/<xx>/<rest>.debug`.
+
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use symbolic_common::Arch;
+
+use crate::symstore::file::{self, FileType, ResourceType};
+
+/// What is known about a file when computing its key. Lookups by identifier
+/// do not know the filename or architecture of every file.
+#[derive(Clone, Eq, PartialEq)]
+pub struct KeyParts {
+    pub file_type: FileType,
+    pub resource_type: ResourceType,
+    pub identifier: String,
+    pub filename: Option<String>,
+    pub arch: Option<Arch>,
+}
+
+impl KeyParts {
+    /// Parses a key of the default layout. Returns `None` for keys which are
+    /// not the key of a file, e.g. the key of a source file.
+    pub fn from_default_key(key: &str) -> Option<Self> {
+        let segments = key.split('/').collect::<Vec<_>>();
+        let [first, second, third] = segments[..] else {
+            return None;
+        };
+        let parts = |file_type, resource_type, identifier: &str, filename: Option<&str>| {
+            Some(Self {
+                file_type,
+                resource_type,
+                identifier: identifier.to_string(),
+                filename: filename.map(|f| f.to_string()),
+                arch: None,
+            })
+        };
+
+        if first == "buildid" {
+            let resource_type = match third {
+                "executable" => ResourceType::Executable,
+                "debuginfo" => ResourceType::DebugInfo,
+                "sourcebundle" => ResourceType::SourceBundle,
+                "minidebuginfo" => ResourceType::MiniDebugInfo,
+                "dwp" => ResourceType::Dwp,
+                _ => return None,
+            };
+            return parts(FileType::Elf, resource_type, second, None);
+        }
+        if let Some(identifier) = second.strip_prefix("mach-uuid-sym-") {
+            if first != "_.dwarf" || third != "_.dwarf" {
+                return None;
+            }
+            return parts(FileType::MachO, ResourceType::DebugInfo, identifier, None);
+        }
+        if first != third {
+            if third != format!("{}.sym", breakpad_stem(first)) {
+                return None;
+            }
+            return parts(
+                FileType::Breakpad,
+                ResourceType::DebugInfo,
+                second,
+                Some(first),
+            );
+        }
+        if let Some(identifier) = second.strip_prefix("mach-uuid-src-") {
+            return parts(
+                FileType::MachO,
+                ResourceType::SourceBundle,
+                identifier,
+                Some(first),
+            );
+        }
+        if let Some(identifier) = second.strip_prefix("mach-uuid-") {
+            return parts(
+                FileType::MachO,
+                ResourceType::Executable,
+                identifier,
+                Some(first),
+            );
+        }
+
+        let lower = first.to_lowercase();
+        let (file_type, resource_type) = if lower.ends_with(".src.zip") {
+            (FileType::SourceBundle, ResourceType::SourceBundle)
+        } else if lower.ends_with(".pdb") {
+            (FileType::Pdb, ResourceType::DebugInfo)
+        } else {
+            (FileType::Pe, ResourceType::Executable)
+        };
+        parts(file_type, resource_type, second, Some(first))
+    }
+
+    fn filename(&self) -> Result<&str> {
+        self.filename
+            .as_deref()
+            .ok_or_else(|| anyhow!("The layout needs the filename of the file"))
+    }
+
+    fn arch(&self) -> Result<Arch> {
+        self.arch
+            .ok_or_else(|| anyhow!("The layout needs the architecture of the file"))
+    }
+}
+
+/// The layout of a server, given in the config as the name of a preset or as
+/// a template such as `{filename}/{id}/{filename}`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Layout {
+    /// debuginfod for ELF and WebAssembly, `mach-uuid-*` for Mach-O, Breakpad
+    /// for Breakpad symbols and SymSrv for everything else.
+    #[default]
+    Default,
+    /// `buildid/<id>/<resource>` for every file.
+    Debuginfod,
+    /// SymSrv for every file, following the SSQP conventions for ELF and
+    /// Mach-O files (e.g. `_.debug/elf-buildid-sym-<id>/_.debug`).
+    SymSrv,
+    /// Breakpad for Breakpad symbols and SymSrv for everything else, without
+    /// the SSQP conventions.
+    Breakpad,
+    /// The `.build-id` tree which LLDB and GDB search for ELF files, and the
+    /// default layout for everything else.
+    Lldb,
+    Template(Template),
+}
+
+impl TryFrom<String> for Layout {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        if value.contains(['{', '}']) {
+            return Ok(Layout::Template(Template::parse(&value)?));
+        }
+        match value.to_lowercase().as_str() {
+            "default" => Ok(Layout::Default),
+            "debuginfod" => Ok(Layout::Debuginfod),
+            "symsrv" => Ok(Layout::SymSrv),
+            "breakpad" => Ok(Layout::Breakpad),
+            "lldb" => Ok(Layout::Lldb),
+            _ => Err(anyhow!(
+                "Unknown layout '{}', expected one of default, debuginfod, symsrv, breakpad, lldb or a template",
+                value
+            )),
+        }
+    }
+}
+
+impl Layout {
+    /// Computes the key of a file. Fails if the layout needs a part of the key
+    /// which is not known.
+    pub fn key(&self, parts: &KeyParts) -> Result<String> {
+        let key = match self {
+            Layout::Default => default_key(parts),
+            Layout::Debuginfod => Ok(debuginfod_key(parts)),
+            Layout::SymSrv => match parts.file_type {
+                FileType::Elf => ssqp_elf_key(parts),
+                FileType::MachO => mach_key(parts),
+                FileType::Breakpad => breakpad_key(parts),
+                _ => symsrv_key(parts),
+            },
+            Layout::Breakpad => match parts.file_type {
+                FileType::Breakpad => breakpad_key(parts),
+                _ => symsrv_key(parts),
+            },
+            Layout::Lldb => match parts.file_type {
+                FileType::Elf => build_id_key(parts),
+                _ => default_key(parts),
+            },
+            Layout::Template(template) => template.render(parts),
+        }?;
+        // Filenames and identifiers are read from the files, so a key could
+        // otherwise name a path outside the store
+        if !is_valid_key(&key) {
+            bail!("Key '{}' is not a relative path", key);
+        }
+        Ok(key)
+    }
+}
+
+/// Whether `key` is a relative path which stays inside the store.
+fn is_valid_key(key: &str) -> bool {
+    key.split('/').all(file::is_valid_name)
+}
+
+fn default_key(parts: &KeyParts) -> Result<String> {
+    match parts.file_type {
+        FileType::Elf | FileType::Wasm => Ok(debuginfod_key(parts)),
+        FileType::MachO => mach_key(parts),
+        FileType::Breakpad => breakpad_key(parts),
+        FileType::Pdb | FileType::Pe | FileType::PortablePdb | FileType::SourceBundle => {
+            symsrv_key(parts)
+        }
+    }
+}
+
+fn debuginfod_key(parts: &KeyParts) -> String {
+    format!(
+        "buildid/{}/{}",
+        parts.identifier,
+        resource_name(parts.resource_type)
+    )
+}
+
+fn symsrv_key(parts: &KeyParts) -> Result<String> {
+    let filename = parts.filename()?;
+    Ok(format!("{filename}/{}/{filename}", parts.identifier))
+}
+
+fn mach_key(parts: &KeyParts) -> Result<String> {
+    let identifier = &parts.identifier;
+    match parts.resource_type {
+        ResourceType::Executable => {
+            let filename = parts.filename()?;
+            Ok(format!("{filename}/mach-uuid-{identifier}/{filename}"))
+        }
+        // Mach-O files have neither MiniDebugInfo nor split DWARF
+        ResourceType::DebugInfo | ResourceType::MiniDebugInfo | ResourceType::Dwp => {
+            Ok(format!("_.dwarf/mach-uuid-sym-{identifier}/_.dwarf"))
+        }
+        ResourceType::SourceBundle => {
+            let filename = parts.filename()?;
+            Ok(format!("{filename}/mach-uuid-src-{identifier}/{filename}"))
+        }
+    }
+}
+
+fn ssqp_elf_key(parts: &KeyParts) -> Result<String> {
+    let identifier = &parts.identifier;
+    match parts.resource_type {
+        ResourceType::Executable => {
+            let filename = parts.filename()?;
+            Ok(format!("{filename}/elf-buildid-{identifier}/{filename}"))
+        }
+        ResourceType::DebugInfo => Ok(format!("_.debug/elf-buildid-sym-{identifier}/_.debug")),
+        ResourceType::SourceBundle => {
+            let filename = parts.filename()?;
+            Ok(format!(
+                "{filename}/elf-buildid-src-{identifier}/{filename}"
+            ))
+        }
+        // SSQP has no convention for these
+        ResourceType::MiniDebugInfo | ResourceType::Dwp => Ok(debuginfod_key(parts)),
+    }
+}
+
+fn breakpad_key(parts: &KeyParts) -> Result<String> {
+    let filename = parts.filename()?;
+    Ok(format!(
+        "{filename}/{}/{}.sym",
+        parts.identifier,
+        breakpad_stem(filename)
+    ))
+}
+
+/// Breakpad drops the extension of PDBs, e.g. `foo.pdb` -> `foo.sym`
+fn breakpad_stem(filename: &str) -> &str {
+    match filename.rsplit_once('.') {
+        Some((stem, ext)) if ext.eq_ignore_ascii_case("pdb") => stem,
+        _ => filename,
+    }
+}
+
+/// The key of an ELF file in a `.build-id` tree, which splits the build-id
+/// after its first byte, e.g. `.build-id/ab/cdef.debug`.
+fn build_id_key(parts: &KeyParts) -> Result<String> {
+    let identifier = &parts.identifier;
+    if identifier.len() < 3 || !identifier.is_ascii() {
+        bail!("Invalid build-id '{}'", identifier);
+    }
+    let (dir, rest) = identifier.split_at(2);
+    let extension = match parts.resource_type {
+        ResourceType::Executable => "",
+        ResourceType::DebugInfo => ".debug",
+        ResourceType::SourceBundle => ".src.zip",
+        ResourceType::MiniDebugInfo => ".minidebuginfo",
+        ResourceType::Dwp => ".dwp",
+    };
+    Ok(format!(".build-id/{dir}/{rest}{extension}"))
+}
+
+fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Pe => "pe",
+        FileType::Pdb => "pdb",
+        FileType::Elf => "elf",
+        FileType::MachO => "macho",
+        FileType::Breakpad => "breakpad",
+        FileType::Wasm => "wasm",
+        FileType::PortablePdb => "portablepdb",
+        FileType::SourceBundle => "sourcebundle",
+    }
+}
+
+fn resource_name(resource_type: ResourceType) -> &'static str {
+    match resource_type {
+        ResourceType::Executable => "executable",
+        ResourceType::DebugInfo => "debuginfo",
+        ResourceType::SourceBundle => "sourcebundle",
+        ResourceType::MiniDebugInfo => "minidebuginfo",
+        ResourceType::Dwp => "dwp",
+    }
+}
+
+/// A layout given as a template, in which `{id}`, `{filename}`, `{type}`,
+/// `{resource}` and `{arch}` are replaced with the parts of the key.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Template(Vec<Segment>);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Text(String),
+    Id,
+    Filename,
+    Type,
+    Resource,
+    Arch,
+}
+
+impl Template {
+    fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let Some(start) = rest.find(['{', '}']) else {
+                segments.push(Segment::Text(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = match rest[start..].strip_prefix('{') {
+                Some(tail) => tail.find('}').map(|end| start + 1 + end),
+                None => None,
+            }
+            .ok_or_else(|| anyhow!("Unbalanced braces in layout '{}'", template))?;
+            segments.push(match &rest[start + 1..end] {
+                "id" => Segment::Id,
+                "filename" => Segment::Filename,
+                "type" => Segment::Type,
+                "resource" => Segment::Resource,
+                "arch" => Segment::Arch,
+                name => bail!("Unknown variable '{{{}}}' in layout '{}'", name, template),
+            });
+            rest = &rest[end + 1..];
+        }
+
+        if !segments.contains(&Segment::Id) {
+            bail!("Layout '{}' does not contain '{{id}}'", template);
+        }
+        if !is_valid_key(template) {
+            bail!("Layout '{}' is not a relative path", template);
+        }
+        Ok(Self(segments))
+    }
+
+    fn render(&self, parts: &KeyParts) -> Result<String> {
+        let mut key = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Text(text) => key.push_str(text),
+                Segment::Id => key.push_str(&parts.identifier),
+                Segment::Filename => key.push_str(parts.filename()?),
+                Segment::Type => key.push_str(type_name(parts.file_type)),
+                Segment::Resource => key.push_str(resource_name(parts.resource_type)),
+                Segment::Arch => key.push_str(parts.arch()?.name()),
+            }
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(
+        file_type: FileType,
+        resource_type: ResourceType,
+        identifier: &str,
+        filename: &str,
+    ) -> KeyParts {
+        KeyParts {
+            file_type,
+            resource_type,
+            identifier: identifier.to_string(),
+            filename: Some(filename.to_string()),
+            arch: Some(Arch::Amd64),
+        }
+    }
+
+    #[test]
+    fn default_keys_round_trip() {
+        let build_id = "15dfff3239aa7c3b16a71e6b2e3b6e4009dab998";
+        let uuid = "a1b2c3d4e5f60718293a4b5c6d7e8f90";
+        let pdb_id = "3844DBB920174967BE7AA4A2C20430FA2";
+        let cases = [
+            parts(FileType::Elf, ResourceType::Executable, build_id, "ls"),
+            parts(FileType::Elf, ResourceType::DebugInfo, build_id, "ls"),
+            parts(FileType::Elf, ResourceType::SourceBundle, build_id, "ls"),
+            parts(FileType::Elf, ResourceType::MiniDebugInfo, build_id, "ls"),
+            parts(FileType::Elf, ResourceType::Dwp, build_id, "ls.dwp"),
+            parts(FileType::MachO, ResourceType::Executable, uuid, "app"),
+            parts(FileType::MachO, ResourceType::DebugInfo, uuid, "app"),
+            parts(
+                FileType::MachO,
+                ResourceType::SourceBundle,
+                uuid,
+                "app.src.zip",
+            ),
+            parts(
+                FileType::Breakpad,
+                ResourceType::DebugInfo,
+                pdb_id,
+                "foo.pdb",
+            ),
+            parts(
+                FileType::Breakpad,
+                ResourceType::DebugInfo,
+                pdb_id,
+                "libfoo.so",
+            ),
+            parts(FileType::Pdb, ResourceType::DebugInfo, pdb_id, "foo.pdb"),
+            parts(
+                FileType::Pe,
+                ResourceType::Executable,
+                "5F3A1B2C1d000",
+                "foo.dll",
+            ),
+            parts(
+                FileType::SourceBundle,
+                ResourceType::SourceBundle,
+                pdb_id,
+                "foo.src.zip",
+            ),
+        ];
+        for original in cases {
+            let key = Layout::Default.key(&original).unwrap();
+            let parsed = KeyParts::from_default_key(&key)
+                .unwrap_or_else(|| panic!("'{}' is not a default key", key));
+            assert!(parsed.file_type == original.file_type, "{}", key);
+            assert!(parsed.resource_type == original.resource_type, "{}", key);
+            assert_eq!(parsed.identifier, original.identifier);
+            assert_eq!(Layout::Default.key(&parsed).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn other_keys_are_not_default_keys() {
+        for key in [
+            "buildid/15dfff32/source/usr/src/a.c",
+            "buildid/15dfff32/unknown",
+            "_.dwarf/mach-uuid-sym-a1b2/other",
+            "foo.pdb/ABC1",
+            "foo.pdb/ABC1/bar.dll",
+        ] {
+            assert!(KeyParts::from_default_key(key).is_none(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn templates() {
+        let template = Template::parse("{type}/{arch}/{filename}/{id}/{resource}").unwrap();
+        let file = parts(FileType::Elf, ResourceType::DebugInfo, "abcd", "ls");
+        assert_eq!(
+            template.render(&file).unwrap(),
+            "elf/x86_64/ls/abcd/debuginfo"
+        );
+        let unknown_arch = KeyParts { arch: None, ..file };
+        assert!(template.render(&unknown_arch).is_err());
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in [
+            "{filename}/{filename}",
+            "{id",
+            "id}/{filename}",
+            "{id}/{{filename}}",
+            "{id}/{name}",
+            "{}/{id}",
+            "/srv/{id}",
+            "../{id}",
+            "{id}/../{filename}",
+            "{id}//{filename}",
+            "{id}/",
+        ] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+        assert!(Layout::try_from("flat".to_string()).is_err());
+        assert!(Layout::try_from("LLDB".to_string()).unwrap() == Layout::Lldb);
+    }
+
+    #[test]
+    fn keys_stay_inside_the_store() {
+        for filename in ["..", ".", "", "a\\b", "a\0b"] {
+            let file = parts(FileType::Pdb, ResourceType::DebugInfo, "ABC1", filename);
+            assert!(Layout::SymSrv.key(&file).is_err(), "{}", filename);
+            assert!(Layout::Default.key(&file).is_err(), "{}", filename);
+        }
+        let file = parts(FileType::Elf, ResourceType::DebugInfo, "../../x", "ls");
+        assert!(Layout::Debuginfod.key(&file).is_err());
+        let template = Layout::try_from("{filename}/{id}".to_string()).unwrap();
+        let file = parts(FileType::Pe, ResourceType::Executable, "ABC1", "..");
+        assert!(template.key(&file).is_err());
+        let file = parts(FileType::Pe, ResourceType::Executable, "ABC1", "..foo.dll");
+        assert_eq!(template.key(&file).unwrap(), "..foo.dll/ABC1");
+    }
+
+    #[test]
+    fn short_build_ids_have_no_build_id_key() {
+        let file = parts(FileType::Elf, ResourceType::DebugInfo, "01", "ls");
+        assert!(Layout::Lldb.key(&file).is_err());
+        let file = KeyParts {
+            identifier: "0102".to_string(),
+            ..file
+        };
+        assert_eq!(Layout::Lldb.key(&file).unwrap(), ".build-id/01/02.debug");
+    }
+}
@@ -0,0 +1,26 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::trace;
+
+use crate::symstore::file::{self, FileInfo};
+
+/// Writes the slice of the fat Mach-O file `file` to its own file in `dir`,
+/// so that the slice can be uploaded on its own. The slice keeps the name and
+/// key of `file`.
+pub fn extract(file: &FileInfo, dir: &Path) -> Result<FileInfo> {
+    trace!(
+        "Extracting slice {:?} of {}",
+        file.slice,
+        file.path.display()
+    );
+    let view = file.open()?;
+    let obj = file::parse_object(&view, file)?;
+
+    let (mut out, path) = file::keep_temp_file(dir, "")?;
+    out.write_all(obj.data())
+        .context(format!("Failed to write '{}'", path.display()))?;
+
+    file.derived(path, file.resource_type, file.name.clone())
+}
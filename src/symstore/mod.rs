@@ -1 +1,8 @@
+pub mod archive;
+pub mod breakpad;
+pub mod dwp;
+pub mod elf;
 pub mod file;
+pub mod layout;
+pub mod macho;
+pub mod sourcebundle;
@@ -0,0 +1,40 @@
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::{info, trace};
+use symbolic_debuginfo::sourcebundle::SourceBundleWriter;
+
+use crate::symstore::file::{self, FileInfo, ResourceType};
+
+/// Creates a source bundle in `dir` containing the source files referenced by
+/// the debug info of `file`. Only source files which exist on this machine
+/// are added. Returns `None` if none of the source files could be found.
+///
+/// The bundle is named `<name>.src.zip` on the server so that it is keyed next
+/// to the file it was created from.
+pub fn create(file: &FileInfo, dir: &Path) -> Result<Option<FileInfo>> {
+    trace!("Creating source bundle for {}", file.path.display());
+    let view = file.open()?;
+    let obj = file::parse_object(&view, file)?;
+
+    let name = format!("{}.src.zip", file.name);
+    let (out, path) = file::keep_temp_file(dir, ".src.zip")?;
+    let writer = SourceBundleWriter::start(BufWriter::new(out))
+        .context(format!("Failed to create '{}'", path.display()))?;
+    let written = writer.write_object(&obj, &file.name).context(format!(
+        "Failed to write source bundle for '{}'",
+        file.path.display()
+    ))?;
+    if !written {
+        info!("No sources found for {}", file.path.display());
+        let _ = std::fs::remove_file(&path);
+        return Ok(None);
+    }
+
+    Ok(Some(file.derived(
+        path,
+        ResourceType::SourceBundle,
+        name,
+    )?))
+}
@@ -0,0 +1,555 @@
+// The `000Admin` folder in which Microsoft's symstore.exe records the
+// transactions of a store, so that the files added by a transaction can be
+// listed and deleted again. Each transaction has a file named after its ID
+// listing the folders it added files to, and a line in `server.txt`
+// (transactions which have not been deleted) and `history.txt` (every
+// transaction, including deletions). These files are written exactly like
+// symstore.exe writes them, so that its tools keep working on the store.
+
+use std::convert::TryFrom;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use tokio::runtime::Runtime;
+
+use crate::config::{RemoteStorage, RemoteStorageType};
+use crate::s3;
+
+pub const ADMIN_DIR: &str = "000Admin";
+const LAST_ID: &str = "lastid.txt";
+const SERVER: &str = "server.txt";
+const HISTORY: &str = "history.txt";
+/// Marker which symstore.exe writes to the root of every store.
+pub const PING_ME: &str = "pingme.txt";
+
+/// What is recorded about the uploaded files, like the `/t`, `/v` and `/c`
+/// options of symstore.exe.
+#[derive(Clone, Debug, Default)]
+pub struct TransactionInfo {
+    pub product: String,
+    pub version: String,
+    pub comment: String,
+}
+
+impl TransactionInfo {
+    /// Fails if a field cannot be recorded, since the fields are quoted
+    /// without escaping.
+    pub fn validate(&self) -> Result<()> {
+        for (name, value) in [
+            ("product", &self.product),
+            ("version", &self.version),
+            ("comment", &self.comment),
+        ] {
+            if value.contains(['"', '\r', '\n']) {
+                return Err(anyhow!(
+                    "The {} of a transaction cannot contain quotes or line breaks",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A transaction which added files to a store.
+pub struct Transaction {
+    pub id: String,
+    /// Date of the transaction as `MM/DD/YYYY`.
+    pub date: String,
+    /// Time of the transaction as `HH:MM:SS`.
+    pub time: String,
+    pub info: TransactionInfo,
+}
+
+impl Transaction {
+    /// Parses a line of `server.txt`. Returns `None` for lines which are not
+    /// an addition.
+    fn parse(line: &str) -> Option<Self> {
+        let fields = split_fields(line);
+        match fields.as_slice() {
+            [id, op, _, date, time, product, version, comment, ..] if op == "add" => Some(Self {
+                id: id.clone(),
+                date: date.clone(),
+                time: time.clone(),
+                info: TransactionInfo {
+                    product: product.clone(),
+                    version: version.clone(),
+                    comment: comment.clone(),
+                },
+            }),
+            _ => None,
+        }
+    }
+
+    /// Seconds since the Unix epoch at which the transaction was recorded.
+    /// The date and time are in local time, like symstore.exe writes them.
+    pub fn timestamp(&self) -> Option<u64> {
+        let time = NaiveDateTime::parse_from_str(
+            &format!("{} {}", self.date, self.time),
+            "%m/%d/%Y %H:%M:%S",
+        )
+        .ok()?;
+        let time = Local.from_local_datetime(&time).earliest()?;
+        u64::try_from(time.timestamp()).ok()
+    }
+}
+
+/// Where the `000Admin` folder and the files of a store are kept.
+pub trait Store {
+    /// Reads the file `name` of `000Admin`. Returns `None` if it does not
+    /// exist.
+    fn read(&self, name: &str) -> Result<Option<String>>;
+    /// Creates the file `name` of `000Admin`. Returns false if it already
+    /// exists.
+    fn create(&self, name: &str, contents: &str) -> Result<bool>;
+    /// Replaces the contents of the file `name` of `000Admin` with the result
+    /// of `f`, which is passed the current contents.
+    fn update(&self, name: &str, f: &dyn Fn(&str) -> String) -> Result<()>;
+    /// Appends `line` to the file `name` of `000Admin`.
+    fn append(&self, name: &str, line: &str) -> Result<()> {
+        self.update(name, &|contents| format!("{contents}{line}"))
+    }
+    /// Deletes the file `path` of the store. Returns false if it does not
+    /// exist.
+    fn delete_file(&self, path: &str) -> Result<bool>;
+}
+
+/// A store in a folder, e.g. a share.
+pub struct PathStore {
+    root: PathBuf,
+}
+
+impl PathStore {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn admin(&self, name: &str) -> PathBuf {
+        self.root.join(ADMIN_DIR).join(name)
+    }
+}
+
+impl Store for PathStore {
+    fn read(&self, name: &str) -> Result<Option<String>> {
+        let path = self.admin(name);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error).context(format!("Failed to read '{}'", path.display())),
+        }
+    }
+
+    fn create(&self, name: &str, contents: &str) -> Result<bool> {
+        use std::io::Write;
+
+        let admin = self.root.join(ADMIN_DIR);
+        std::fs::create_dir_all(&admin)
+            .context(format!("Failed to create '{}'", admin.display()))?;
+        let ping_me = self.root.join(PING_ME);
+        if !ping_me.exists() {
+            std::fs::File::create(&ping_me)
+                .context(format!("Failed to create '{}'", ping_me.display()))?;
+        }
+
+        let path = admin.join(name);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                file.write_all(contents.as_bytes())
+                    .context(format!("Failed to write '{}'", path.display()))?;
+                Ok(true)
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(error) => Err(error).context(format!("Failed to create '{}'", path.display())),
+        }
+    }
+
+    fn update(&self, name: &str, f: &dyn Fn(&str) -> String) -> Result<()> {
+        use std::io::Write;
+
+        let contents = self.read(name)?.unwrap_or_default();
+        let path = self.admin(name);
+        // The new contents replace the file at once, so that readers never
+        // see a partly written file
+        let admin = self.root.join(ADMIN_DIR);
+        let mut file = tempfile::NamedTempFile::new_in(&admin)
+            .context(format!("Failed to create a file in '{}'", admin.display()))?;
+        file.write_all(f(&contents).as_bytes())
+            .context(format!("Failed to write '{}'", file.path().display()))?;
+        file.persist(&path)
+            .context(format!("Failed to write '{}'", path.display()))?;
+        Ok(())
+    }
+
+    fn append(&self, name: &str, line: &str) -> Result<()> {
+        use std::io::Write;
+
+        // Appending to the file rather than rewriting it keeps the lines of
+        // concurrent uploads to a share
+        let path = self.admin(name);
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .context(format!("Failed to write '{}'", path.display()))
+    }
+
+    fn delete_file(&self, path: &str) -> Result<bool> {
+        let path = self.root.join(path);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => {
+                return Err(error).context(format!("Failed to delete '{}'", path.display()))
+            }
+        }
+
+        // Remove the folders which are left empty, e.g. `foo.pdb` once its
+        // last identifier is deleted
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir == self.root || std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+        Ok(true)
+    }
+}
+
+/// Opens the store of `server`. Only path stores and S3 and B2 buckets keep
+/// transactions.
+pub fn store(
+    server: &RemoteStorage,
+    timeout: Option<Duration>,
+    rt: &Runtime,
+) -> Result<Box<dyn Store>> {
+    match &server.storage_type {
+        RemoteStorageType::Path(c) => Ok(Box::new(PathStore::new(&c.path))),
+        RemoteStorageType::S3(c) => {
+            let client = rt.block_on(s3::s3_client(c, timeout));
+            Ok(Box::new(s3::Store::new(
+                rt.handle().clone(),
+                client,
+                &c.bucket,
+                &c.prefix,
+                &server.retry,
+            )))
+        }
+        RemoteStorageType::B2(c) => {
+            let client = rt.block_on(s3::b2_client(c, timeout))?;
+            Ok(Box::new(s3::Store::new(
+                rt.handle().clone(),
+                client,
+                &c.bucket,
+                &c.prefix,
+                &server.retry,
+            )))
+        }
+        RemoteStorageType::Http(_) | RemoteStorageType::SymbolServer(_) => {
+            bail!("{} does not keep transactions", server)
+        }
+    }
+}
+
+/// A file added by a transaction.
+pub struct AddedFile<'a> {
+    /// Path of the file relative to the store.
+    pub dest: &'a Path,
+    /// Path of the file which was uploaded.
+    pub source: &'a Path,
+}
+
+/// Records the addition of `files` to `store` as a new transaction. Returns
+/// the ID of the transaction.
+///
+/// The file of the transaction lists the folder and the source of each file,
+/// like symstore.exe. As a folder may hold the files of other transactions in
+/// some layouts, the paths of the files are listed in `<id>.files` as well, so
+/// that the transaction can be deleted file by file.
+pub fn add(store: &dyn Store, info: &TransactionInfo, files: &[AddedFile]) -> Result<String> {
+    let contents = files
+        .iter()
+        .map(|added| {
+            let dir = added.dest.parent().unwrap_or_else(|| Path::new(""));
+            format!(
+                "\"{}\",\"{}\"\r\n",
+                windows_path(dir),
+                added.source.display()
+            )
+        })
+        .collect::<String>();
+    let id = create_transaction(store, &contents)?;
+    let paths = files
+        .iter()
+        .map(|added| format!("\"{}\"\r\n", windows_path(added.dest)))
+        .collect::<String>();
+    if !store.create(&files_name(&id), &paths)? {
+        bail!("The files of transaction {} are already recorded", id);
+    }
+
+    let (date, time) = date_time(Local::now());
+    let record = format!(
+        "{id},add,file,{date},{time},\"{}\",\"{}\",\"{}\",\r\n",
+        info.product, info.version, info.comment
+    );
+    store.append(SERVER, &record)?;
+    store.append(HISTORY, &record)?;
+    Ok(id)
+}
+
+/// Returns the transactions of `store` which have not been deleted, oldest
+/// first.
+pub fn list(store: &dyn Store) -> Result<Vec<Transaction>> {
+    let server = store.read(SERVER)?.unwrap_or_default();
+    let mut transactions = server
+        .lines()
+        .filter_map(Transaction::parse)
+        .collect::<Vec<_>>();
+    transactions.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(transactions)
+}
+
+/// What a transaction added to a store, relative to the store.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Added {
+    File(String),
+    /// A folder, for transactions which do not record their files, e.g.
+    /// those of symstore.exe.
+    Dir(String),
+}
+
+/// Returns what the transaction `id` added to `store`: its files if they are
+/// recorded, and its folders otherwise.
+pub fn added(store: &dyn Store, id: &str) -> Result<Vec<Added>> {
+    let contents = match store.read(&files_name(id))? {
+        Some(contents) => contents,
+        None => store
+            .read(id)?
+            .ok_or_else(|| anyhow!("The file of transaction {} is missing", id))?,
+    };
+    parse_added(&contents).with_context(|| format!("Malformed transaction {}", id))
+}
+
+/// Parses the lines of `<id>.files`, which hold the path of a file, or of the
+/// file of a transaction, which hold a folder and the source of a file.
+fn parse_added(contents: &str) -> Result<Vec<Added>> {
+    let mut added = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let entry = match split_fields(line).as_slice() {
+            [file] => Added::File(file.replace('\\', "/")),
+            [dir, _] => Added::Dir(dir.replace('\\', "/")),
+            _ => bail!("Malformed line '{}'", line),
+        };
+        let (Added::File(path) | Added::Dir(path)) = &entry;
+        if !is_store_path(path) {
+            bail!("Malformed line '{}'", line);
+        }
+        if !added.contains(&entry) {
+            added.push(entry);
+        }
+    }
+    Ok(added)
+}
+
+/// Records the deletion of the transaction `id`, after all of its files have
+/// been deleted. Returns the ID of the deletion, which is a transaction as well.
+pub fn delete(store: &dyn Store, id: &str) -> Result<String> {
+    let del_id = create_transaction(store, "")?;
+    store.append(HISTORY, &format!("{del_id},del,{id}\r\n"))?;
+    let prefix = format!("{id},");
+    store.update(SERVER, &|contents| {
+        contents
+            .split_inclusive('\n')
+            .filter(|line| !line.starts_with(&prefix))
+            .collect()
+    })?;
+    Ok(del_id)
+}
+
+/// Creates the file of the next transaction with `contents`. The ID is
+/// claimed by creating the file, so that concurrent uploads to the same store
+/// get different IDs.
+fn create_transaction(store: &dyn Store, contents: &str) -> Result<String> {
+    let last_id = match store.read(LAST_ID)? {
+        Some(last_id) => parse_id(&last_id)?,
+        None => 0,
+    };
+    let mut id = last_id + 1;
+    let name = loop {
+        let name = format!("{id:010}");
+        if store.create(&name, contents)? {
+            break name;
+        }
+        id += 1;
+    };
+    // Never move the last ID backwards, in case a concurrent upload claimed a
+    // later one first
+    store.update(LAST_ID, &|last_id| match parse_id(last_id) {
+        Ok(last_id) if last_id > id => format!("{last_id:010}\r\n"),
+        _ => format!("{name}\r\n"),
+    })?;
+    Ok(name)
+}
+
+/// The name of the file listing the files added by the transaction `id`.
+fn files_name(id: &str) -> String {
+    format!("{id}.files")
+}
+
+fn parse_id(id: &str) -> Result<u64> {
+    id.trim()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Malformed transaction ID '{}'", id.trim()))
+}
+
+/// Splits a line of a `000Admin` file into its comma separated fields,
+/// removing the quotes around quoted fields.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    for c in line.trim_end_matches(['\r', '\n']).chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Whether `path` is a file or folder of the store which a transaction may
+/// delete.
+fn is_store_path(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.as_os_str().is_empty()
+        && !path.starts_with(ADMIN_DIR)
+        && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn windows_path(path: &Path) -> String {
+    path.to_string_lossy().replace('/', "\\")
+}
+
+/// Formats `time` as the `MM/DD/YYYY` date and `HH:MM:SS` time of a
+/// transaction.
+fn date_time(time: DateTime<Local>) -> (String, String) {
+    (
+        time.format("%m/%d/%Y").to_string(),
+        time.format("%H:%M:%S").to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_of_transactions() {
+        let (date, time) = date_time(Local.timestamp_opt(1_792_291_845, 0).unwrap());
+        assert_eq!((date.len(), time.len()), (10, 8));
+        let line = format!("0000000001,add,file,{date},{time},\"app\",\"1\",\"\",");
+        let transaction = Transaction::parse(&line).unwrap();
+        assert_eq!(transaction.timestamp(), Some(1_792_291_845));
+
+        for (date, time) in [
+            ("13/01/2026", "12:00:00"),
+            ("bogus", "12:00:00"),
+            ("01/01/2026", "25:00:00"),
+        ] {
+            let line = format!("0000000001,add,file,{date},{time},\"app\",\"1\",\"\",");
+            assert!(Transaction::parse(&line).unwrap().timestamp().is_none());
+        }
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            split_fields("0000000001,add,file,\"a,b\",\"\",\r\n"),
+            ["0000000001", "add", "file", "a,b", "", ""]
+        );
+        assert_eq!(split_fields(""), [""]);
+        assert!(Transaction::parse("0000000002,del,0000000001").is_none());
+    }
+
+    #[test]
+    fn added_files_and_folders() {
+        let contents = "\"foo.pdb\\ABC1\\foo.pdb\"\r\n\
+                        \"ABC2\"\r\n\
+                        \"bar.pdb\\DEF1\",\"C:\\build\\bar.pdb\"\r\n\
+                        \r\n";
+        assert_eq!(
+            parse_added(contents).unwrap(),
+            [
+                Added::File("foo.pdb/ABC1/foo.pdb".to_string()),
+                Added::File("ABC2".to_string()),
+                Added::Dir("bar.pdb/DEF1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn added_paths_stay_inside_the_store() {
+        for line in [
+            "\"\",\"src\"",
+            "\"\"",
+            "\"..\\x\"",
+            "\"..\",\"src\"",
+            "\"/etc/passwd\"",
+            "\"000Admin\\server.txt\"",
+            "\"a\",\"src\",\"a\\b\"",
+        ] {
+            assert!(parse_added(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn path_store() {
+        let root = tempfile::tempdir().unwrap();
+        let store = PathStore::new(root.path());
+        let dest = Path::new("foo.pdb/ABC1/foo.pdb");
+        std::fs::create_dir_all(root.path().join("foo.pdb/ABC1")).unwrap();
+        std::fs::write(root.path().join(dest), "pdb").unwrap();
+
+        let info = TransactionInfo {
+            product: "app".to_string(),
+            version: "1".to_string(),
+            comment: String::new(),
+        };
+        let files = [AddedFile {
+            dest,
+            source: Path::new("/build/foo.pdb"),
+        }];
+        let id = add(&store, &info, &files).unwrap();
+        assert_eq!(id, "0000000001");
+        assert_eq!(
+            store.read(&id).unwrap().unwrap(),
+            "\"foo.pdb\\ABC1\",\"/build/foo.pdb\"\r\n"
+        );
+        assert_eq!(store.read(LAST_ID).unwrap().unwrap(), "0000000001\r\n");
+        assert_eq!(
+            added(&store, &id).unwrap(),
+            [Added::File("foo.pdb/ABC1/foo.pdb".to_string())]
+        );
+        assert_eq!(list(&store).unwrap().len(), 1);
+
+        assert!(store.delete_file("foo.pdb/ABC1/foo.pdb").unwrap());
+        assert!(!store.delete_file("foo.pdb/ABC1/foo.pdb").unwrap());
+        assert!(!root.path().join("foo.pdb").exists());
+
+        assert_eq!(delete(&store, &id).unwrap(), "0000000002");
+        assert!(list(&store).unwrap().is_empty());
+        let history = store.read(HISTORY).unwrap().unwrap();
+        assert!(history.ends_with("0000000002,del,0000000001\r\n"));
+    }
+}
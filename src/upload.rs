@@ -1,19 +1,29 @@
-use std::io::{Read, Seek};
+use std::collections::HashSet;
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use aws_config::Region;
-use aws_sdk_s3::config::Credentials;
-use aws_sdk_s3::primitives::ByteStream;
-use ignore::WalkBuilder;
-use log::warn;
+use futures::StreamExt;
+use ignore::{WalkBuilder, WalkState};
+use log::{error, warn};
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH};
 use serde::Serialize;
 use sha2::{Digest, Sha256};
-use symbolic_debuginfo::FileFormat;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
 
 use crate::config;
+use crate::http;
+use crate::manifest::{self, Outcome};
+use crate::output::{self, Event};
+use crate::retry;
+use crate::s3;
+use crate::symsrv;
 use crate::symstore;
 use crate::symstore::file::{FileInfo, FileType, ResourceType};
+use crate::symstore::layout::Layout;
+use crate::transaction::{self, TransactionInfo};
 
 #[derive(Serialize)]
 struct SymbolServerUploadRequest {
@@ -23,355 +33,888 @@ struct SymbolServerUploadRequest {
     pub identifier: String,
     pub resource_type: ResourceType,
     pub sha256: String,
+    /// The build the file belongs to, by which the project is pruned.
+    pub product: String,
+    pub version: String,
 }
 
-pub fn upload(
-    search_path: &Path,
-    recursive: bool,
-    server: &config::RemoteStorage,
-    dryrun: bool,
-) -> Result<()> {
-    let obj_files = find_obj_files(search_path, recursive)?;
-    let files = collet_file_info(&obj_files);
-    match &server.storage_type {
-        config::RemoteStorageType::Http(c) => Err(anyhow!(
-            "Upload to HTTP server ({}) not yet implemented!",
-            c.url
-        )),
-        config::RemoteStorageType::S3(c) => {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(upload_to_s3(c, &files, dryrun))
-        }
-        config::RemoteStorageType::B2(c) => {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(upload_to_b2(c, &files, dryrun))
-        }
-        config::RemoteStorageType::SymbolServer(c) => upload_to_symbolserver(c, &files, dryrun),
-        config::RemoteStorageType::Path(c) => copy_to_folder(c, &files, dryrun),
-    }
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum UploadFormat {
+    /// Upload the files as they are found
+    Native,
+    /// Upload Breakpad symbol files generated from the files with debug info
+    Breakpad,
+    /// Upload both the files and the Breakpad symbol files
+    All,
 }
 
-fn is_object_file(path: &Path) -> std::io::Result<FileFormat> {
-    let mut file = std::fs::OpenOptions::new()
-        .write(false)
-        .read(true)
-        .open(path)?;
-
-    let mut magic = [0u8; 256];
-
-    if (file.metadata().unwrap().len() as usize) < magic.len() {
-        return Ok(FileFormat::Unknown);
-    }
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum FatMachO {
+    /// Upload each architecture of a fat Mach-O file as its own file
+    Slices,
+    /// Upload the whole fat Mach-O file under the key of each architecture
+    Container,
+}
 
-    file.read_exact(&mut magic)?;
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Archives {
+    /// Ignore static libraries
+    Skip,
+    /// Upload each member of a static library as its own file
+    Members,
+    /// Upload the whole static library under the key of each member
+    Container,
+}
 
-    Ok(symbolic_debuginfo::peek(&magic, false))
+pub struct UploadOptions {
+    pub recursive: bool,
+    pub dryrun: bool,
+    pub timeout: Option<Duration>,
+    /// Also upload a source bundle for each file with debug info.
+    pub with_sources: bool,
+    /// Decompress the compressed debug sections of ELF files.
+    pub decompress_debug: bool,
+    /// Assemble DWARF packages for ELF files with split DWARF which have
+    /// none.
+    pub assemble_dwp: bool,
+    pub format: UploadFormat,
+    pub fat_macho: FatMachO,
+    pub archives: Archives,
+    /// Maximum number of files uploaded at the same time.
+    pub jobs: usize,
+    /// Where to write the manifest of the upload, if anywhere.
+    pub manifest: Option<PathBuf>,
+    /// What is recorded in the `000Admin` folder of a path store.
+    pub transaction: TransactionInfo,
 }
 
-fn find_obj_files(search_path: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+pub fn upload(
+    search_path: &Path,
+    server: &config::RemoteStorage,
+    options: &UploadOptions,
+    rt: &Runtime,
+) -> Result<()> {
     let search_path = if search_path.to_str() == Some("<self>") {
         std::env::current_exe().context("Failed to get current executable path")?
     } else {
         search_path.to_path_buf()
     };
-
-    if !search_path.exists() {
+    let (mut files, unidentified) =
+        find_object_files(&search_path, options.recursive, options.archives)?;
+    for (path, member) in &unidentified {
+        output::emit(
+            &Event::UnidentifiedMember { path, member },
+            format_args!(
+                "No identifier for member '{}' of '{}'",
+                member,
+                path.display()
+            ),
+        );
+    }
+    if files.is_empty() && search_path.is_file() {
+        if !unidentified.is_empty() {
+            return Err(anyhow!(
+                "No member of \"{}\" has an identifier",
+                search_path.display()
+            ));
+        }
         return Err(anyhow!(
-            "Path \"{}\" doest not exists",
+            "Path \"{}\" is not a valid object file",
             search_path.display()
         ));
     }
 
-    let max_depth = if recursive { None } else { Some(1) };
+    // Keep the folder alive until the upload is done
+    let generated_dir = tempfile::tempdir().context("Failed to create temporary folder")?;
+    let mut generated = Vec::new();
+    if options.with_sources {
+        generated.extend(create_source_bundles(&files, generated_dir.path()));
+    }
+    if options.format != UploadFormat::Native {
+        generated.extend(create_breakpad_symbols(&files, generated_dir.path()));
+    }
+    if options.format == UploadFormat::Breakpad {
+        files.clear();
+    } else {
+        generated.extend(extract_mini_debug_info(&files, generated_dir.path()));
+        if options.assemble_dwp {
+            generated.extend(assemble_dwarf_packages(&files, generated_dir.path()));
+        }
+    }
+    // Files which cannot be prepared fail on their own, like failed uploads
+    let prepared = files
+        .iter_mut()
+        .map(|file| prepare(file, options, generated_dir.path()))
+        .collect::<Vec<_>>();
+    files.extend(generated);
+
+    let keys = files
+        .iter()
+        .zip(
+            prepared
+                .into_iter()
+                .chain(std::iter::repeat_with(|| Ok(()))),
+        )
+        .map(|(file, prepared)| prepared.and_then(|()| file.key(&server.layout)))
+        .collect::<Vec<_>>();
+    // Files without a key in the layout of the server fail on their own
+    let (keyed, keyed_keys): (Vec<_>, Vec<_>) = files
+        .iter()
+        .zip(&keys)
+        .filter_map(|(file, key)| Some((file, key.as_ref().ok()?.clone())))
+        .unzip();
+    let mut results = rt
+        .block_on(upload_files(server, &keyed, &keyed_keys, options))?
+        .into_iter();
+
+    let mut failed = 0;
+    let mut entries = Vec::new();
+    let mut added = Vec::new();
+    for (file, key) in files.iter().zip(keys) {
+        let (key, (result, sha256)) = match key {
+            Ok(key) => (key, results.next().expect("a result for each keyed file")),
+            Err(error) => (String::new(), (Err(error), None)),
+        };
+        if let Err(error) = &result {
+            error!("Failed to upload '{}': {:?}", file.path.display(), error);
+            failed += 1;
+        }
+        let mut entry = manifest::Entry::new(file, key.clone(), server.location(), &result);
+        entry.sha256 = sha256.map(|hash| hash.to_lowercase());
+        output::json(&Event::Upload {
+            path: &entry.path,
+            key: &entry.key,
+            server: &entry.server,
+            outcome: entry.outcome,
+            reason: entry.reason.clone(),
+        });
+        entries.push(entry);
+        // Files which already exist are recorded as well, so that pruning
+        // older builds does not delete them from under this one.
+        if let Ok(Outcome::Uploaded | Outcome::SkippedExisting) = result {
+            added.push((file, key));
+        }
+    }
+    if let Some(path) = &options.manifest {
+        manifest::write(path, &entries)?;
+    }
+    if !added.is_empty() && records_transactions(server) {
+        record_transaction(server, options, &added, rt)?;
+    }
 
-    let files = if search_path.is_dir() {
-        WalkBuilder::new(search_path)
-            .max_depth(max_depth)
-            .git_ignore(false)
-            .build()
-            .filter_map(|v| v.ok())
-            .filter(|x| x.path().is_file())
-            .filter(|x| {
-                is_object_file(x.path()).unwrap_or(FileFormat::Unknown) != FileFormat::Unknown
-            })
-            .map(|x| x.into_path())
-            .collect::<Vec<std::path::PathBuf>>()
+    if failed > 0 {
+        Err(anyhow!(
+            "Failed to upload {} of {} files",
+            failed,
+            files.len()
+        ))
     } else {
-        if is_object_file(&search_path).unwrap_or(FileFormat::Unknown) == FileFormat::Unknown {
-            return Err(anyhow!(
-                "Path \"{}\" is not a valid object file",
-                search_path.display()
-            ));
+        Ok(())
+    }
+}
+
+/// Turns `file` into the file which is uploaded: extracts members of static
+/// libraries and slices of fat Mach-O files unless they are uploaded with their
+/// container, and decompresses debug sections if asked to.
+fn prepare(file: &mut FileInfo, options: &UploadOptions, dir: &Path) -> Result<()> {
+    let mut container = false;
+    if file.member.is_some() {
+        match options.archives {
+            Archives::Container => container = true,
+            _ => *file = symstore::archive::extract(file, dir)?,
         }
-        let files = vec![search_path.to_path_buf()];
-        files
-    };
+    }
+    if file.slice.is_some() {
+        match options.fat_macho {
+            FatMachO::Container => container = true,
+            FatMachO::Slices => *file = symstore::macho::extract(file, dir)?,
+        }
+    }
+    if container {
+        file.file_size = std::fs::metadata(&file.path)
+            .context(format!(
+                "Failed to read metadata of '{}'",
+                file.path.display()
+            ))?
+            .len() as usize;
+    } else if options.decompress_debug
+        && file.file_type == FileType::Elf
+        && file.resource_type == ResourceType::DebugInfo
+    {
+        if let Some(decompressed) = symstore::elf::decompress(file, dir)? {
+            *file = decompressed;
+        }
+    }
+    Ok(())
+}
 
-    Ok(files)
+/// Whether uploads to `server` are recorded as transactions. Path stores
+/// always record them like symstore.exe, buckets only when they are pruned.
+fn records_transactions(server: &config::RemoteStorage) -> bool {
+    match &server.storage_type {
+        config::RemoteStorageType::Path(_) => true,
+        config::RemoteStorageType::S3(_) | config::RemoteStorageType::B2(_) => {
+            server.retention.is_some()
+        }
+        _ => false,
+    }
 }
 
-fn collet_file_info(files: &[PathBuf]) -> Vec<FileInfo> {
-    files
+/// Records the files added to `server` as a symstore transaction.
+fn record_transaction(
+    server: &config::RemoteStorage,
+    options: &UploadOptions,
+    added: &[(&FileInfo, String)],
+    rt: &Runtime,
+) -> Result<()> {
+    let dests = added
         .iter()
-        .filter_map(|path| match symstore::file::file_to_info(path) {
-            Ok(info) => {
-                if let Some(info) = info {
-                    Some(info)
-                } else {
-                    warn!("{} has no key", path.display());
-                    None
-                }
-            }
-            Err(_err) => {
-                println!("Error parsing: {}", path.display());
-                None
-            }
+        .map(|(_, key)| match &server.storage_type {
+            config::RemoteStorageType::Path(c) => symsrv::store_path(key, symsrv::is_two_tier(c)),
+            _ => PathBuf::from(key),
         })
-        .collect()
+        .collect::<Vec<_>>();
+    let files = added
+        .iter()
+        .zip(&dests)
+        .map(|((file, _), dest)| transaction::AddedFile {
+            dest,
+            source: &file.origin,
+        })
+        .collect::<Vec<_>>();
+    let store = transaction::store(server, options.timeout, rt)?;
+    let id = transaction::add(&*store, &options.transaction, &files)?;
+    let location = server.location();
+    output::emit(
+        &Event::Transaction {
+            id: &id,
+            server: &location,
+        },
+        format_args!("Recorded transaction {} in '{}'", id, location),
+    );
+    Ok(())
 }
 
-async fn upload_to_s3_helper(
-    prefix: &str,
-    files: &[FileInfo],
-    dryrun: bool,
-    client: aws_sdk_s3::Client,
-    bucket: &String,
-) -> Result<()> {
-    for file in files {
-        let key = file.key();
-        let full_key = format!("{}{}", prefix, &key);
-        println!(
-            "uploading '{}' to s3 bucket '{}' with key '{}'",
-            file.path.display(),
-            bucket,
-            full_key
-        );
-        if !dryrun {
-            if client
-                .head_object()
-                .bucket(bucket)
-                .key(&full_key)
-                .send()
-                .await
-                .is_ok()
-            {
-                warn!(
-                    "Skipping {} -> {} since the key already exists on server",
-                    file.path.display(),
-                    full_key
-                );
-                continue;
+/// The outcome of uploading a file, along with its hash if requested.
+type FileResult = (Result<Outcome>, Option<String>);
+
+/// Uploads `files` to `server` under `keys`, running up to `options.jobs`
+/// uploads at the same time. Returns the result of each file, in the order of
+/// `files`.
+async fn upload_files(
+    server: &config::RemoteStorage,
+    files: &[&FileInfo],
+    keys: &[String],
+    options: &UploadOptions,
+) -> Result<Vec<FileResult>> {
+    let dryrun = options.dryrun;
+    let jobs = options.jobs.max(1);
+    let hash = options.manifest.is_some();
+    let policy = &server.retry;
+    match &server.storage_type {
+        config::RemoteStorageType::Http(c) => {
+            let client = &http::client(options.timeout)?;
+            let auth = &http::authorization(c)?;
+            for_each_file(files, keys, jobs, hash, move |file, key| {
+                upload_to_http(c, client, auth.as_ref(), policy, file, key, dryrun)
+            })
+            .await
+        }
+        config::RemoteStorageType::S3(c) => {
+            let client = &s3::s3_client(c, options.timeout).await;
+            for_each_file(files, keys, jobs, hash, move |file, key| {
+                let key = format!("{}{}", c.prefix, key);
+                async move {
+                    upload_to_s3(
+                        client,
+                        &c.bucket,
+                        c.part_size_mib,
+                        policy,
+                        file,
+                        &key,
+                        dryrun,
+                    )
+                    .await
+                }
+            })
+            .await
+        }
+        config::RemoteStorageType::B2(c) => {
+            let client = &s3::b2_client(c, options.timeout).await?;
+            for_each_file(files, keys, jobs, hash, move |file, key| {
+                let key = format!("{}{}", c.prefix, key);
+                async move {
+                    upload_to_s3(
+                        client,
+                        &c.bucket,
+                        c.part_size_mib,
+                        policy,
+                        file,
+                        &key,
+                        dryrun,
+                    )
+                    .await
+                }
+            })
+            .await
+        }
+        config::RemoteStorageType::SymbolServer(c) => {
+            const SERVICE: &str = "com.symboserver.symbols";
+            const USERNAME: &str = "symbolserver";
+            let entry = keyring::Entry::new(SERVICE, USERNAME)?;
+            let token = &entry.get_password()?;
+            let client = &http::client(options.timeout)?;
+            for_each_file(files, keys, jobs, hash, move |file, key| async move {
+                let outcome =
+                    upload_to_symbolserver(c, client, token, policy, file, key, options).await;
+                if let Ok(Outcome::Uploaded) = outcome {
+                    output::text(format_args!(
+                        "Uploaded '{}' to symbolserver.com",
+                        file.path.display()
+                    ));
+                }
+                outcome
+            })
+            .await
+        }
+        config::RemoteStorageType::Path(c) => {
+            let two_tier = symsrv::is_two_tier(c);
+            if two_tier && !dryrun {
+                symsrv::init_two_tier(&c.path)?;
             }
-            let body = ByteStream::from_path(&file.path).await?;
-            client
-                .put_object()
-                .bucket(bucket)
-                .key(&full_key)
-                .body(body)
-                .send()
-                .await
-                .context(format!("Failed to upload '{}' to S3", file.path.display()))?;
+            for_each_file(files, keys, jobs, hash, move |file, key| {
+                copy_to_folder(c, two_tier, policy, file, key, dryrun)
+            })
+            .await
         }
     }
-
-    Ok(())
 }
 
-async fn upload_to_s3(config: &config::S3Config, files: &[FileInfo], dryrun: bool) -> Result<()> {
-    let builder = aws_config::profile::ProfileFileCredentialsProvider::builder();
-    let builder = if let Some(profile) = &config.profile {
-        builder.profile_name(profile)
-    } else {
-        builder
-    };
-    let provider = builder.build();
-    let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-        .credentials_provider(provider)
-        .region(Region::new(config.region.clone()))
-        .load()
+/// Runs `f` for each file and its key with at most `jobs` files in flight,
+/// hashing each file as well if `hash` is set.
+async fn for_each_file<'a, F, Fut>(
+    files: &'a [&'a FileInfo],
+    keys: &'a [String],
+    jobs: usize,
+    hash: bool,
+    mut f: F,
+) -> Result<Vec<FileResult>>
+where
+    F: FnMut(&'a FileInfo, &'a str) -> Fut,
+    Fut: Future<Output = Result<Outcome>>,
+{
+    let results = futures::stream::iter(files.iter().copied().zip(keys))
+        .map(|(file, key)| {
+            let outcome = f(file, key);
+            async move {
+                let outcome = outcome.await;
+                let sha256 = if hash {
+                    sha256(&file.path).await.ok()
+                } else {
+                    None
+                };
+                (outcome, sha256)
+            }
+        })
+        .buffered(jobs)
+        .collect()
         .await;
-    let client = aws_sdk_s3::Client::new(&sdk_config);
-    upload_to_s3_helper(&config.prefix, files, dryrun, client, &config.bucket).await
+    Ok(results)
 }
 
-async fn upload_to_b2(config: &config::B2Config, files: &[FileInfo], dryrun: bool) -> Result<()> {
-    let b2_creds = match &config.account_id {
-        Some(id) => b2creds::Credentials::from_file(None, Some(id))?,
-        None => b2creds::Credentials::locate()?,
-    };
+/// Opens `path` as a streaming request body. Returns the body along with its
+/// length, since a streamed body has no length of its own.
+async fn file_body(path: &Path) -> Result<(reqwest::Body, u64)> {
+    let f = tokio::fs::File::open(path)
+        .await
+        .context(format!("Failed to open '{}'", path.display()))?;
+    let len = f.metadata().await?.len();
+    let stream = tokio_util::io::ReaderStream::new(f);
+    Ok((reqwest::Body::wrap_stream(stream), len))
+}
 
-    let creds = Credentials::new(
-        &b2_creds.application_key_id,
-        &b2_creds.application_key,
-        None,
-        None,
-        "b2",
-    );
+/// The path of a static library along with the name of one of its members
+/// which has no identifier.
+type UnidentifiedMember = (PathBuf, String);
+
+/// Finds and identifies the object files in `search_path`. Directories are
+/// walked with one thread per core, with each thread identifying the files it
+/// finds. The result is sorted by path so that it does not depend on the order
+/// in which the threads finish.
+///
+/// Also returns the members of static libraries which are not objects with an
+/// identifier, along with the path of their library.
+fn find_object_files(
+    search_path: &Path,
+    recursive: bool,
+    archives: Archives,
+) -> Result<(Vec<FileInfo>, Vec<UnidentifiedMember>)> {
+    if !search_path.exists() {
+        return Err(anyhow!(
+            "Path \"{}\" doest not exists",
+            search_path.display()
+        ));
+    }
 
-    let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-        .credentials_provider(creds)
-        .region("b2")
-        .endpoint_url(config.endpoint.clone())
-        .load()
-        .await;
-    let client = aws_sdk_s3::Client::new(&sdk_config);
+    if !search_path.is_dir() {
+        let (files, unidentified) = identify(search_path, archives);
+        let unidentified = unidentified
+            .into_iter()
+            .map(|member| (search_path.to_path_buf(), member))
+            .collect();
+        return Ok((files, unidentified));
+    }
 
-    upload_to_s3_helper(&config.prefix, files, dryrun, client, &config.bucket).await
+    let max_depth = if recursive { None } else { Some(1) };
+    let (tx, rx) = std::sync::mpsc::channel();
+    WalkBuilder::new(search_path)
+        .max_depth(max_depth)
+        .git_ignore(false)
+        .build_parallel()
+        .run(|| {
+            let tx = tx.clone();
+            Box::new(move |entry| {
+                if let Ok(entry) = entry {
+                    if entry.path().is_file() {
+                        let found = identify(entry.path(), archives);
+                        let _ = tx.send((entry.into_path(), found));
+                    }
+                }
+                WalkState::Continue
+            })
+        });
+    drop(tx);
+
+    let mut files = Vec::new();
+    let mut unidentified = Vec::new();
+    for (path, (infos, members)) in rx {
+        files.extend(infos);
+        unidentified.extend(members.into_iter().map(|member| (path.clone(), member)));
+    }
+    files.sort_by_cached_key(|file| (file.path.clone(), file.key(&Layout::Default).ok()));
+    // Files may be linked to by several files, or found on their own as well
+    files.dedup_by(|a, b| {
+        a.path == b.path && a.key(&Layout::Default).ok() == b.key(&Layout::Default).ok()
+    });
+    unidentified.sort();
+    Ok((files, unidentified))
 }
 
-fn upload_to_symbolserver(
-    config: &config::SymbolServerConfig,
-    files: &[FileInfo],
-    dryrun: bool,
-) -> Result<()> {
-    const SERVICE: &str = "com.symboserver.symbols";
-    const USERNAME: &str = "symbolserver";
-    let entry = keyring::Entry::new(SERVICE, USERNAME)?;
-    let token = entry.get_password()?;
-    let client = reqwest::blocking::Client::builder().build().unwrap();
+/// Identifies the objects in the file at `path`. Returns an empty list if it
+/// is not an object file. Unless `archives` is `Skip`, the members of static
+/// libraries are identified too, and the names of those members which have no
+/// identifier are returned along with the objects.
+///
+/// The files holding parts of the debug info of ELF files are added as well.
+fn identify(path: &Path, archives: Archives) -> (Vec<FileInfo>, Vec<String>) {
+    let (mut files, unidentified) = identify_file(path, archives);
+    add_linked_files(&mut files);
+    (files, unidentified)
+}
 
-    for file in files {
-        println!(
-            "uploading '{}' to symbolserver with key '{}'",
-            file.path.display(),
-            file.key()
-        );
-        if !dryrun {
-            let mut f = match std::fs::File::open(&file.path) {
-                Ok(f) => f,
-                Err(error) => {
-                    warn!(
-                        "Failed to upload {}. Could not open file due to error: {}",
-                        file.path.display(),
-                        error
-                    );
-                    continue;
+fn identify_file(path: &Path, archives: Archives) -> (Vec<FileInfo>, Vec<String>) {
+    if archives != Archives::Skip {
+        match symstore::archive::members_to_info(path) {
+            Ok(Some(members)) => return (members.objects, members.unidentified),
+            Ok(None) => {}
+            Err(error) => {
+                warn!("{error:?}");
+                return (Vec::new(), Vec::new());
+            }
+        }
+    }
+    match symstore::file::file_to_info(path) {
+        Ok(infos) => (infos, Vec::new()),
+        Err(_err) => {
+            output::text(format_args!("Error parsing: {}", path.display()));
+            (Vec::new(), Vec::new())
+        }
+    }
+}
+
+/// Adds the files linked to by the ELF files in `files`, and by the linked
+/// files in turn, e.g. the supplementary file of a separate debug file.
+fn add_linked_files(files: &mut Vec<FileInfo>) {
+    let mut seen = files
+        .iter()
+        .map(|file| file.path.clone())
+        .collect::<HashSet<_>>();
+    let mut index = 0;
+    while index < files.len() {
+        if files[index].file_type == FileType::Elf {
+            match symstore::elf::linked_files(&files[index]) {
+                Ok(linked) => {
+                    for file in linked {
+                        if seen.insert(file.path.clone()) {
+                            files.push(file);
+                        }
+                    }
                 }
-            };
-            let mut hasher = Sha256::new();
-            if let Err(error) = std::io::copy(&mut f, &mut hasher) {
-                warn!(
-                    "Failed to read '{}' due to {:?} in order to generate hash. Skipping upload",
-                    file.path.display(),
+                Err(error) => warn!(
+                    "Failed to find linked files of {}: {:?}",
+                    files[index].path.display(),
                     error
-                );
-                continue;
+                ),
             }
-            let hash = hasher.finalize();
-            f.seek(std::io::SeekFrom::Start(0)).unwrap();
-
-            let url = config
-                .url
-                .as_deref()
-                .unwrap_or("https://api.symbolserver.com");
-
-            let request = SymbolServerUploadRequest {
-                file_name: file.path.file_name().unwrap().to_str().unwrap().to_string(),
-                file_size: file.file_size,
-                file_type: file.file_type,
-                identifier: file.identifier.clone(),
-                resource_type: file.resource_type,
-                sha256: data_encoding::HEXUPPER.encode(&hash),
-            };
-
-            let res = match client
-                .post(format!("{}/symbols/{}/upload/create", url, config.project))
-                .bearer_auth(&token)
-                .json(&request)
-                .send()
-            {
-                Ok(response) => response,
-                Err(error) => {
-                    warn!(
-                        "Failed to upload {}. Upload failed due to error: {}",
-                        file.path.display(),
-                        error
-                    );
-                    continue;
-                }
-            };
+        }
+        index += 1;
+    }
+}
 
-            if !res.status().is_success() {
+fn create_source_bundles(files: &[FileInfo], dir: &Path) -> Vec<FileInfo> {
+    files
+        .iter()
+        .filter(|file| file.resource_type == ResourceType::DebugInfo)
+        .filter_map(|file| match symstore::sourcebundle::create(file, dir) {
+            Ok(bundle) => bundle,
+            Err(error) => {
                 warn!(
-                    "Upload of '{}' did not succeed. {}",
+                    "Failed to create source bundle for {}: {:?}",
                     file.path.display(),
-                    res.text().unwrap()
+                    error
                 );
-                continue;
+                None
             }
+        })
+        .collect()
+}
 
-            let signed_url = res.text().unwrap();
-            let res = match client.put(&signed_url).body(f).send() {
-                Ok(response) => response,
-                Err(error) => {
-                    warn!(
-                        "Failed to upload {} to presigned url {}. Upload failed due to error: {}",
-                        file.path.display(),
-                        signed_url,
-                        error
-                    );
-                    continue;
-                }
-            };
-
-            if !res.status().is_success() {
+fn create_breakpad_symbols(files: &[FileInfo], dir: &Path) -> Vec<FileInfo> {
+    files
+        .iter()
+        .filter(|file| file.resource_type == ResourceType::DebugInfo)
+        // Symbol files are already Breakpad, and portable PDBs describe
+        // managed code which Breakpad has no use for
+        .filter(|file| !matches!(file.file_type, FileType::Breakpad | FileType::PortablePdb))
+        .filter_map(|file| match symstore::breakpad::create(file, dir) {
+            Ok(symbols) => symbols,
+            Err(error) => {
                 warn!(
-                    "Upload of '{}' via pre-signed URL did not succeed. {}",
+                    "Failed to create Breakpad symbols for {}: {:?}",
                     file.path.display(),
-                    res.text().unwrap()
+                    error
                 );
-                continue;
+                None
             }
+        })
+        .collect()
+}
 
-            let res = match client
-                .post(format!("{}/symbols/{}/upload/finish", url, config.project))
-                .bearer_auth(&token)
-                .json(&request)
-                .send()
-            {
-                Ok(response) => response,
-                Err(error) => {
-                    warn!(
-                        "Failed to upload {}. Upload failed due to error: {}",
-                        file.path.display(),
-                        error
-                    );
-                    continue;
-                }
-            };
-
-            if !res.status().is_success() {
+fn extract_mini_debug_info(files: &[FileInfo], dir: &Path) -> Vec<FileInfo> {
+    files
+        .iter()
+        .filter(|file| file.file_type == FileType::Elf)
+        .filter_map(|file| match symstore::elf::mini_debug_info(file, dir) {
+            Ok(info) => info,
+            Err(error) => {
                 warn!(
-                    "Upload of '{}' failed to be marked successful. {}",
+                    "Failed to extract MiniDebugInfo of {}: {:?}",
                     file.path.display(),
-                    res.text().unwrap()
+                    error
                 );
-                continue;
+                None
             }
-        }
-        println!("Uploaded '{}' to symbolserver.com", file.path.display());
-    }
-    Ok(())
+        })
+        .collect()
 }
 
-fn copy_to_folder(config: &config::PathConfig, files: &[FileInfo], dryrun: bool) -> Result<()> {
+/// Assembles a DWARF package for each build-id which has split DWARF but no
+/// DWARF package among `files`.
+fn assemble_dwarf_packages(files: &[FileInfo], dir: &Path) -> Vec<FileInfo> {
+    let mut done = files
+        .iter()
+        .filter(|file| file.resource_type == ResourceType::Dwp)
+        .map(|file| file.identifier.clone())
+        .collect::<HashSet<_>>();
+    let mut packages = Vec::new();
     for file in files {
-        let dest = config.path.join(file.key());
-        if let Some(parent) = dest.parent() {
-            std::fs::create_dir_all(parent).context(format!(
-                "Failed to create destination folder '{}'",
-                parent.display()
-            ))?;
+        if file.file_type != FileType::Elf
+            || file.member.is_some()
+            || !matches!(
+                file.resource_type,
+                ResourceType::Executable | ResourceType::DebugInfo
+            )
+            || done.contains(&file.identifier)
+        {
+            continue;
         }
-        println!("Copying '{}' to '{}'", file.path.display(), dest.display());
-        if !dryrun {
-            std::fs::copy(&file.path, &dest).context(format!(
-                "Failed to copy '{}' to '{}'",
+        match symstore::dwp::assemble(file, dir) {
+            Ok(Some(package)) => {
+                done.insert(file.identifier.clone());
+                packages.push(package);
+            }
+            Ok(None) => {}
+            Err(error) => warn!(
+                "Failed to assemble DWARF package for {}: {:?}",
                 file.path.display(),
-                dest.display()
-            ))?;
+                error
+            ),
         }
     }
+    packages
+}
 
-    Ok(())
+async fn upload_to_s3(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    part_size_mib: Option<u64>,
+    policy: &config::RetryConfig,
+    file: &FileInfo,
+    full_key: &str,
+    dryrun: bool,
+) -> Result<Outcome> {
+    output::text(format_args!(
+        "uploading '{}' to s3 bucket '{}' with key '{}'",
+        file.path.display(),
+        bucket,
+        full_key
+    ));
+    if dryrun {
+        return Ok(Outcome::DryRun);
+    }
+
+    let exists = retry::retry_async(policy, &format!("Lookup of '{full_key}'"), || async {
+        match client
+            .head_object()
+            .bucket(bucket)
+            .key(full_key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(error)
+                if error
+                    .as_service_error()
+                    .map(|e| e.is_not_found())
+                    .unwrap_or(false) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(s3::sdk_error(error)),
+        }
+    })
+    .await
+    .context(format!(
+        "Failed to look up '{}' in bucket '{}'",
+        full_key, bucket
+    ))?;
+    if exists {
+        warn!(
+            "Skipping {} -> {} since the key already exists on server",
+            file.path.display(),
+            full_key
+        );
+        return Ok(Outcome::SkippedExisting);
+    }
+    s3::put_file(client, bucket, full_key, &file.path, part_size_mib, policy)
+        .await
+        .context(format!("Failed to upload '{}' to S3", file.path.display()))?;
+    Ok(Outcome::Uploaded)
+}
+
+async fn upload_to_http(
+    config: &config::HttpConfig,
+    client: &reqwest::Client,
+    auth: Option<&HeaderValue>,
+    policy: &config::RetryConfig,
+    file: &FileInfo,
+    key: &str,
+    dryrun: bool,
+) -> Result<Outcome> {
+    let url = http::url(config, key)?;
+    output::text(format_args!(
+        "uploading '{}' to '{}'",
+        file.path.display(),
+        url
+    ));
+    if dryrun {
+        return Ok(Outcome::DryRun);
+    }
+
+    let authorize = |request: reqwest::RequestBuilder| match auth {
+        Some(auth) => request.header(AUTHORIZATION, auth),
+        None => request,
+    };
+
+    let res = retry::retry_async(policy, &format!("Lookup of '{url}'"), || async {
+        Ok(authorize(client.head(url.clone())).send().await?)
+    })
+    .await
+    .context(format!("Failed to check if '{url}' exists"))?;
+    if res.status().is_success() {
+        warn!(
+            "Skipping {} -> {} since the key already exists on server",
+            file.path.display(),
+            url
+        );
+        return Ok(Outcome::SkippedExisting);
+    }
+
+    retry::retry_async(policy, &format!("Upload to '{url}'"), || async {
+        let (body, len) = file_body(&file.path).await?;
+        let request = match config.method {
+            config::HttpMethod::Put => client.put(url.clone()),
+            config::HttpMethod::Post => client.post(url.clone()),
+        };
+        authorize(request)
+            .header(CONTENT_LENGTH, len)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    })
+    .await
+    .context(format!(
+        "Failed to upload '{}' to '{}'",
+        file.path.display(),
+        url
+    ))?;
+    Ok(Outcome::Uploaded)
+}
+
+async fn sha256(path: &Path) -> Result<String> {
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .context(format!("Failed to open '{}'", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = f
+            .read(&mut buffer)
+            .await
+            .context(format!("Failed to read '{}'", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(data_encoding::HEXUPPER.encode(&hasher.finalize()))
+}
+
+async fn upload_to_symbolserver(
+    config: &config::SymbolServerConfig,
+    client: &reqwest::Client,
+    token: &str,
+    policy: &config::RetryConfig,
+    file: &FileInfo,
+    key: &str,
+    options: &UploadOptions,
+) -> Result<Outcome> {
+    output::text(format_args!(
+        "uploading '{}' to symbolserver with key '{}'",
+        file.path.display(),
+        key
+    ));
+    if options.dryrun {
+        return Ok(Outcome::DryRun);
+    }
+
+    let url = config
+        .url
+        .as_deref()
+        .unwrap_or("https://api.symbolserver.com");
+
+    let request = SymbolServerUploadRequest {
+        file_name: file.name.clone(),
+        file_size: file.file_size,
+        file_type: file.file_type,
+        identifier: file.identifier.clone(),
+        resource_type: file.resource_type,
+        sha256: sha256(&file.path).await?,
+        product: options.transaction.product.clone(),
+        version: options.transaction.version.clone(),
+    };
+
+    let create = format!("{}/symbols/{}/upload/create", url, config.project);
+    let signed_url = retry::retry_async(policy, &format!("Request to '{create}'"), || async {
+        let res = client
+            .post(&create)
+            .bearer_auth(token)
+            .json(&request)
+            .send()
+            .await?;
+        Ok(http::success(res, "Upload did not succeed")
+            .await?
+            .text()
+            .await?)
+    })
+    .await?;
+
+    retry::retry_async(policy, "Upload to presigned url", || async {
+        let (body, len) = file_body(&file.path).await?;
+        let res = client
+            .put(&signed_url)
+            .header(CONTENT_LENGTH, len)
+            .body(body)
+            .send()
+            .await
+            .context(format!("Failed to upload to presigned url {signed_url}"))?;
+        http::success(res, "Upload via pre-signed URL did not succeed").await?;
+        Ok(())
+    })
+    .await?;
+
+    let finish = format!("{}/symbols/{}/upload/finish", url, config.project);
+    retry::retry_async(policy, &format!("Request to '{finish}'"), || async {
+        let res = client
+            .post(&finish)
+            .bearer_auth(token)
+            .json(&request)
+            .send()
+            .await?;
+        http::success(res, "Upload failed to be marked successful").await?;
+        Ok(())
+    })
+    .await?;
+    Ok(Outcome::Uploaded)
+}
+
+async fn copy_to_folder(
+    config: &config::PathConfig,
+    two_tier: bool,
+    policy: &config::RetryConfig,
+    file: &FileInfo,
+    key: &str,
+    dryrun: bool,
+) -> Result<Outcome> {
+    let dest = config.path.join(symsrv::store_path(key, two_tier));
+    output::text(format_args!(
+        "Copying '{}' to '{}'",
+        file.path.display(),
+        dest.display()
+    ));
+    if dryrun {
+        return Ok(Outcome::DryRun);
+    }
+
+    let exists = tokio::fs::try_exists(&dest)
+        .await
+        .context(format!("Failed to look up '{}'", dest.display()))?;
+    if exists {
+        warn!(
+            "Skipping {} -> {} since the file already exists",
+            file.path.display(),
+            dest.display()
+        );
+        return Ok(Outcome::SkippedExisting);
+    }
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await.context(format!(
+            "Failed to create destination folder '{}'",
+            parent.display()
+        ))?;
+    }
+
+    let what = format!("Copy to '{}'", dest.display());
+    retry::retry_async(policy, &what, || async {
+        Ok(tokio::fs::copy(&file.path, &dest).await?)
+    })
+    .await
+    .context(format!(
+        "Failed to copy '{}' to '{}'",
+        file.path.display(),
+        dest.display()
+    ))?;
+    Ok(Outcome::Uploaded)
 }